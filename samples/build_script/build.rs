@@ -0,0 +1,10 @@
+use std::{env, fs, path::Path};
+
+fn main() {
+    let out_dir = env::var("OUT_DIR").unwrap();
+    fs::write(Path::new(&out_dir).join("generated.txt"), "generated").unwrap();
+
+    println!("cargo:rustc-env=BUILD_SCRIPT_VALUE=from build script");
+    println!("cargo:rustc-cfg=from_build_script");
+    println!("cargo:rerun-if-changed=build.rs");
+}
@@ -0,0 +1,8 @@
+fn main() {
+    println!("{}", env!("BUILD_SCRIPT_VALUE"));
+}
+
+#[test]
+fn test_build_script_env() {
+    assert_eq!("from build script", env!("BUILD_SCRIPT_VALUE"));
+}
@@ -0,0 +1,5 @@
+#[test]
+fn test_out_dir() {
+    let generated = include_str!(concat!(env!("OUT_DIR"), "/generated.txt"));
+    assert_eq!("generated", generated);
+}
@@ -0,0 +1,18 @@
+use std::{env, path::Path};
+
+/// Cargo sets these when it runs tests, not only when it compiles them
+#[test]
+fn test_runtime_env() {
+    let out_dir = env::var("OUT_DIR").unwrap();
+    assert!(Path::new(&out_dir).join("generated.txt").is_file());
+    assert_eq!(
+        Ok("from build script".to_owned()),
+        env::var("BUILD_SCRIPT_VALUE")
+    );
+    assert_eq!(
+        Ok(env!("CARGO_MANIFEST_DIR").to_owned()),
+        env::var("CARGO_MANIFEST_DIR")
+    );
+    assert_eq!(Ok("build_script".to_owned()), env::var("CARGO_PKG_NAME"));
+    assert_eq!(Ok("0.1.0".to_owned()), env::var("CARGO_PKG_VERSION"));
+}
@@ -0,0 +1,4 @@
+#[test]
+fn test_in_broken() {
+    let _: u32 = "this doesn't compile";
+}
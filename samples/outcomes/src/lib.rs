@@ -0,0 +1,56 @@
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, thread, time::Duration};
+
+    #[test]
+    fn passes() {
+        println!("to stdout");
+        eprintln!("to stderr");
+    }
+
+    #[test]
+    fn fails() {
+        println!("before failing");
+        assert_eq!(1, 2);
+    }
+
+    #[test]
+    #[should_panic]
+    fn panics() {
+        panic!("expected");
+    }
+
+    #[test]
+    #[ignore]
+    fn ignored() {}
+
+    #[test]
+    #[ignore = "too slow"]
+    fn ignored_with_reason() {}
+
+    #[test]
+    fn sleeps() {
+        thread::sleep(Duration::from_secs(60));
+    }
+
+    #[test]
+    fn no_trailing_newline() {
+        print!("\n\nfailures:\nno newline");
+    }
+
+    #[test]
+    fn in_package_dir() {
+        let manifest = fs::read_to_string("Cargo.toml").unwrap();
+        assert!(manifest.contains("name = \"outcomes\""));
+    }
+
+    #[test]
+    fn reads_env() {
+        assert_eq!(Ok("value".to_owned()), env::var("SEACAN_TEST_ENV"));
+    }
+
+    #[test]
+    fn without_rustc_bootstrap() {
+        assert_eq!(None, env::var_os("RUSTC_BOOTSTRAP"));
+    }
+}
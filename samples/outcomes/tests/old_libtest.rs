@@ -0,0 +1,18 @@
+//! Acts like a version of libtest that doesn't support JSON output
+
+use std::{env, process};
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "-Z") {
+        eprintln!("error: Unrecognized option: 'Z'");
+        process::exit(101);
+    }
+
+    if args.iter().any(|arg| arg == "--list") {
+        println!("only_test: test");
+    } else {
+        println!("\nrunning 1 test\ntest only_test ... ok\n");
+        println!("test result: ok. 1 passed; 0 failed; 0 ignored; 0 measured; 0 filtered out\n");
+    }
+}
@@ -0,0 +1,11 @@
+use std::{env, fs, process, thread, time::Duration};
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=SEACAN_BUILD_SCRIPT_PID");
+
+    // Tests set this to check we kill the build script along with cargo
+    if let Some(pid_file) = env::var_os("SEACAN_BUILD_SCRIPT_PID") {
+        fs::write(pid_file, process::id().to_string()).unwrap();
+        thread::sleep(Duration::from_secs(60));
+    }
+}
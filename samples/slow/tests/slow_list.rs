@@ -0,0 +1,6 @@
+use std::{thread, time::Duration};
+
+// Doesn't use libtest, so `--list` hangs like everything else
+fn main() {
+    thread::sleep(Duration::from_secs(60));
+}
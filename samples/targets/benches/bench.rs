@@ -0,0 +1,4 @@
+#[test]
+fn test_in_bench() {
+    assert_eq!(42, targets::answer());
+}
@@ -0,0 +1,4 @@
+#[test]
+fn test_answer() {
+    assert_eq!(42, targets::answer());
+}
@@ -0,0 +1,3 @@
+fn main() {
+    assert_eq!(42, targets::answer());
+}
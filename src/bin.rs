@@ -1,19 +1,22 @@
 //!
 //! Main entrypoint: [`bin::Compiler`]
 
-use std::{
-    io::BufReader,
-    path::PathBuf,
-    process::{Command, Stdio},
-};
+use std::process::Command;
 
-use camino::Utf8PathBuf;
-use cargo_metadata::CompilerMessage;
+use cargo_metadata::{CompilerMessage, Message, PackageId};
 use derivative::Derivative;
 use tracing::instrument;
 
+#[cfg(feature = "tokio")]
+use crate::run::run_cargo_async;
 use crate::{
-    handle_compiler_msg, BuildError, ExecutableArtifact, FeatureSpec, PackageSpec, MSG_FORMAT,
+    event::{handle_output_event, handle_progress_event},
+    handle_compiler_msg,
+    metadata::Metadata,
+    options::{cargo_options_builders, CargoOptions},
+    progress::handle_progress,
+    run::{run_cargo, BuildState, CargoOutput},
+    BuildError, BuildEvent, BuildOutcome, ExecutableArtifact, PackageSpec, Progress,
 };
 
 /// Compile a binary
@@ -29,171 +32,337 @@ use crate::{
 #[derive(Derivative)]
 #[derivative(Debug)]
 pub struct Compiler {
-    workspace: Option<PathBuf>,
-    package: PackageSpec,
-    name: String,
-    is_example: bool,
+    selection: Selection,
+    #[derivative(Debug = "ignore")]
+    on_compiler_msg: Option<Box<dyn FnMut(CompilerMessage) + Send>>,
     #[derivative(Debug = "ignore")]
-    on_compiler_msg: Option<Box<dyn FnMut(CompilerMessage)>>,
-    target_dir: Option<Utf8PathBuf>,
-    features: Option<FeatureSpec>,
-    is_release: bool,
+    on_progress: Option<Box<dyn FnMut(Progress) + Send>>,
+    #[derivative(Debug = "ignore")]
+    on_event: Option<Box<dyn FnMut(BuildEvent) + Send>>,
+    opts: CargoOptions,
 }
 
 impl Compiler {
-    // TODO: fn default_bin
-    //   See <https://github.com/rust-lang/cargo/issues/9491>
+    /// Compile the binary `cargo run` would pick.
+    ///
+    /// We use `cargo metadata` to find the package's `default-run` binary, its
+    /// only binary, or the binary with the name of the package, in that order.
+    /// Otherwise we return [`BuildError::NoDefaultBin`]. Like `cargo run`,
+    /// with [`PackageSpec::Any`] the package is the one in
+    /// [`Self::workspace`], or any member of a virtual workspace. See
+    /// <https://github.com/rust-lang/cargo/issues/9491>
+    #[must_use]
+    pub fn default_bin() -> Self {
+        Self::new(Selection::DefaultBin)
+    }
 
     /// Compile a binary.
     ///
     /// Note: By default the default binary has the name of the crate.
     #[must_use]
     pub fn bin(name: impl Into<String>) -> Self {
-        Self::new(name, false)
+        Self::new(Selection::Targets(Targets::Bins(vec![name.into()])))
     }
 
     /// Compile an example.
     #[must_use]
     pub fn example(name: impl Into<String>) -> Self {
-        Self::new(name, true)
+        Self::new(Selection::Targets(Targets::Examples(vec![name.into()])))
+    }
+
+    /// Compile several binaries at once. Use with [`Self::compile_all`].
+    #[must_use]
+    pub fn bins<S: Into<String>>(names: impl IntoIterator<Item = S>) -> Self {
+        Self::new(Selection::Targets(Targets::Bins(
+            names.into_iter().map(Into::into).collect(),
+        )))
+    }
+
+    /// Compile several examples at once. Use with [`Self::compile_all`].
+    #[must_use]
+    pub fn examples<S: Into<String>>(names: impl IntoIterator<Item = S>) -> Self {
+        Self::new(Selection::Targets(Targets::Examples(
+            names.into_iter().map(Into::into).collect(),
+        )))
+    }
+
+    /// Compile every binary (i.e. `--bins`). Use with [`Self::compile_all`].
+    #[must_use]
+    pub fn all_bins() -> Self {
+        Self::new(Selection::Targets(Targets::AllBins))
     }
 
-    fn new(name: impl Into<String>, is_example: bool) -> Self {
+    /// Compile every example (i.e. `--examples`). Use with
+    /// [`Self::compile_all`].
+    #[must_use]
+    pub fn all_examples() -> Self {
+        Self::new(Selection::Targets(Targets::AllExamples))
+    }
+
+    fn new(selection: Selection) -> Self {
         Self {
-            workspace: None,
-            package: PackageSpec::Any,
-            name: name.into(),
-            is_example,
+            selection,
             on_compiler_msg: None,
-            target_dir: None,
-            features: None,
-            is_release: false,
+            on_progress: None,
+            on_event: None,
+            opts: CargoOptions::new(),
         }
     }
 
-    /// The directory to run cargo in.
-    ///
-    /// By default the current working directory.
-    pub fn workspace(&mut self, path: impl Into<PathBuf>) -> &mut Self {
-        self.workspace = Some(path.into());
-        self
-    }
+    cargo_options_builders!();
 
-    /// The package the binary is in.
+    /// Compile the described executable
+    ///
+    /// # Errors
+    ///
+    /// If cargo couldn't be run or the build failed. We try to give a more
+    /// specific [`BuildError`] than [`BuildError::Cargo`] where we can.
     ///
-    /// By default [`PackageSpec::Any`].
-    pub fn package(&mut self, package: PackageSpec) -> &mut Self {
-        self.package = package;
-        self
+    /// [`BuildError::ExpectedOneArtifact`] if cargo built more or fewer
+    /// executables. If you describe several use [`Self::compile_all`] instead.
+    #[instrument(err)]
+    pub fn compile(&mut self) -> Result<ExecutableArtifact, BuildError> {
+        only_artifact(self.compile_all()?)
     }
 
-    /// Callback for compiler messages.
+    /// Compile every described executable in one invocation of cargo
+    ///
+    /// # Errors
     ///
-    /// Regardless of if you specify this compiler messages will be logged at
-    /// debug level using [`tracing`].
-    pub fn on_compiler_msg(&mut self, cb: impl FnMut(CompilerMessage) + 'static) -> &mut Self {
-        self.on_compiler_msg = Some(Box::new(cb));
-        self
+    /// If cargo couldn't be run or the build failed. We try to give a more
+    /// specific [`BuildError`] than [`BuildError::Cargo`] where we can.
+    #[instrument(err)]
+    pub fn compile_all(&mut self) -> Result<Vec<ExecutableArtifact>, BuildError> {
+        Ok(self.compile_outcome()?.artifacts)
     }
 
-    /// Where to put the build artifacts.
+    /// Like [`Self::compile_all`], but also returns the rest of what cargo
+    /// reported: diagnostics, which units were fresh, and
+    /// [`Self::timings`].
+    ///
+    /// # Errors
     ///
-    /// By default this is whatever cargo chooses by default.
-    pub fn target_dir(&mut self, target_dir: impl Into<Utf8PathBuf>) -> &mut Self {
-        self.target_dir = Some(target_dir.into());
-        self
+    /// See [`Self::compile_all`].
+    #[instrument(err)]
+    pub fn compile_outcome(&mut self) -> Result<BuildOutcome<Vec<ExecutableArtifact>>, BuildError> {
+        let deadline = self.opts.deadline();
+        let targets = match &self.selection {
+            Selection::DefaultBin => self.resolve_default_bin(&self.opts.metadata(&deadline)?)?,
+            Selection::Targets(targets) => targets.clone(),
+        };
+
+        let mut build = BuildState::new();
+        let (status, stderr) = run_cargo(self.command(&targets), &deadline, |output| {
+            self.handle_output(output, &mut build);
+        })?;
+        build.finish(&self.opts, status, stderr)
     }
 
-    /// Enable or disable feature flags.
+    /// Like [`Self::compile`], but doesn't block the thread.
     ///
-    /// By default this is whatever cargo chooses by default.
-    pub fn features(&mut self, features: FeatureSpec) -> &mut Self {
-        self.features = Some(features);
-        self
+    /// Cargo is killed if you drop the future before it finishes.
+    ///
+    /// # Errors
+    ///
+    /// See [`Self::compile`].
+    #[cfg(feature = "tokio")]
+    #[instrument(err)]
+    pub async fn compile_async(&mut self) -> Result<ExecutableArtifact, BuildError> {
+        only_artifact(self.compile_all_async().await?)
     }
 
-    /// If we should build in release mode.
-    pub fn release(&mut self, is_release: bool) -> &mut Self {
-        self.is_release = is_release;
-        self
+    /// Like [`Self::compile_all`], but doesn't block the thread.
+    ///
+    /// Cargo is killed if you drop the future before it finishes.
+    ///
+    /// # Errors
+    ///
+    /// See [`Self::compile_all`].
+    #[cfg(feature = "tokio")]
+    #[instrument(err)]
+    pub async fn compile_all_async(&mut self) -> Result<Vec<ExecutableArtifact>, BuildError> {
+        Ok(self.compile_outcome_async().await?.artifacts)
     }
 
-    /// Compile the described executable
+    /// Like [`Self::compile_outcome`], but doesn't block the thread.
+    ///
+    /// Cargo is killed if you drop the future before it finishes.
+    ///
+    /// # Errors
+    ///
+    /// See [`Self::compile_all`].
+    #[cfg(feature = "tokio")]
     #[instrument(err)]
-    pub fn compile(&mut self) -> Result<ExecutableArtifact, BuildError> {
-        let mut cmd = Command::new("cargo");
+    pub async fn compile_outcome_async(
+        &mut self,
+    ) -> Result<BuildOutcome<Vec<ExecutableArtifact>>, BuildError> {
+        let deadline = self.opts.deadline();
+        let targets = match &self.selection {
+            Selection::DefaultBin => {
+                // Not in one expression so that we don't hold `&self` across
+                // the await, which would need the callbacks to be `Sync`
+                let metadata = self.opts.metadata_async(&deadline).await?;
+                self.resolve_default_bin(&metadata)?
+            }
+            Selection::Targets(targets) => targets.clone(),
+        };
 
-        cmd.arg("build")
-            .arg(MSG_FORMAT)
-            .args(&["--package", self.package.as_repr()])
-            .stderr(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stdin(Stdio::null());
+        let mut build = BuildState::new();
+        let (status, stderr) = run_cargo_async(self.command(&targets), &deadline, |output| {
+            self.handle_output(output, &mut build);
+        })
+        .await?;
+        build.finish(&self.opts, status, stderr)
+    }
 
-        if let Some(features) = &self.features {
-            cmd.args(features.to_args());
+    /// `cargo build` for `targets`
+    fn command(&self, targets: &Targets) -> Command {
+        let mut cmd = if let Targets::PackageBin(package, _) = targets {
+            let mut opts = self.opts.clone();
+            opts.package = package.clone().into();
+            opts.command("build")
+        } else {
+            self.opts.command("build")
+        };
+        match targets {
+            Targets::Bins(names) => {
+                for name in names {
+                    cmd.args(["--bin", name]);
+                }
+            }
+            Targets::Examples(names) => {
+                for name in names {
+                    cmd.args(["--example", name]);
+                }
+            }
+            Targets::AllBins => {
+                cmd.arg("--bins");
+            }
+            Targets::AllExamples => {
+                cmd.arg("--examples");
+            }
+            Targets::PackageBin(_, name) => {
+                cmd.args(["--bin", name]);
+            }
         }
+        cmd
+    }
 
-        if let Some(ref workspace) = self.workspace {
-            cmd.current_dir(workspace);
+    fn handle_output(&mut self, output: CargoOutput, build: &mut BuildState<ExecutableArtifact>) {
+        handle_output_event(&output, &mut self.on_event);
+        if let Some(progress) = build.progress.record(&output) {
+            handle_progress_event(&progress, &mut self.on_event);
+            handle_progress(progress, &mut self.on_progress);
         }
-
-        if self.is_release {
-            cmd.arg("--release");
+        if let CargoOutput::Message(msg) = output {
+            self.handle_msg(msg, build);
         }
+    }
 
-        if let Some(ref target_dir) = self.target_dir {
-            cmd.args(&["--target-dir", target_dir.as_str()]);
+    fn handle_msg(&mut self, msg: Message, build: &mut BuildState<ExecutableArtifact>) {
+        build.record(&msg);
+        match msg {
+            Message::CompilerMessage(msg) => {
+                handle_compiler_msg(msg, &mut self.on_compiler_msg);
+            }
+            Message::CompilerArtifact(art) => {
+                if let Some(art) = ExecutableArtifact::maybe_from(
+                    art,
+                    self.opts.profile.name("dev"),
+                    self.opts.target_triple.clone(),
+                    &build.build_scripts,
+                ) {
+                    build.artifacts.push(art);
+                }
+            }
+            _ => {}
         }
+    }
 
-        if self.is_example {
-            cmd.args(&["--example", &self.name]);
-        } else {
-            cmd.args(&["--bin", &self.name]);
-        }
+    /// The binary [`Self::default_bin`] describes
+    fn resolve_default_bin(&self, metadata: &Metadata) -> Result<Targets, BuildError> {
+        let packages = match &self.opts.package {
+            // Like `cargo run` without `--package`
+            PackageSpec::Any => metadata.current(),
+            spec => metadata.select(spec)?,
+        };
 
-        let mut cmd = cmd.spawn()?;
+        if let [package] = packages.as_slice() {
+            if let Some(name) = metadata.default_run(&package.id) {
+                return Ok(Targets::PackageBin(package.id.clone(), name.to_owned()));
+            }
+        }
 
-        let stdout = cmd.stdout.take().unwrap();
-        let stderr = cmd.stderr.take().unwrap();
+        let bins: Vec<_> = packages
+            .iter()
+            .flat_map(|package| {
+                package
+                    .targets
+                    .iter()
+                    .filter(|target| target.kind.iter().any(|kind| kind == "bin"))
+                    .map(move |target| (&package.id, &target.name))
+            })
+            .collect();
 
-        let mut artifact = None;
+        if let [(package, name)] = bins.as_slice() {
+            return Ok(Targets::PackageBin((*package).clone(), (*name).clone()));
+        }
 
-        let messages = cargo_metadata::Message::parse_stream(BufReader::new(stdout));
-        for msg in messages {
-            match msg? {
-                cargo_metadata::Message::CompilerMessage(msg) => {
-                    handle_compiler_msg(msg, &mut self.on_compiler_msg)
-                }
-                cargo_metadata::Message::CompilerArtifact(art) => {
-                    if art.executable.is_none() {
-                        continue;
-                    }
-                    assert!(
-                    artifact.is_none(),
-                    "Expected cargo build with --bin or --example to only produce one executable"
-                );
-                    artifact = Some(art);
-                }
-                _ => {}
+        if let [package] = packages.as_slice() {
+            if let Some((id, name)) = bins.iter().find(|(_, name)| **name == package.name) {
+                return Ok(Targets::PackageBin((*id).clone(), (*name).clone()));
             }
         }
 
-        if cmd.wait()?.success() {
-            let artifact = artifact
-                .expect("If cargo build exits with success should have built an executable");
-            Ok(ExecutableArtifact::maybe_from(artifact).expect("Artifact has executable"))
-        } else {
-            Err(BuildError::from_stderr(stderr))
-        }
+        Err(BuildError::NoDefaultBin {
+            candidates: bins.into_iter().map(|(_, name)| name.clone()).collect(),
+        })
+    }
+}
+
+/// The one executable [`Compiler::compile`] built
+fn only_artifact(mut artifacts: Vec<ExecutableArtifact>) -> Result<ExecutableArtifact, BuildError> {
+    match artifacts.len() {
+        1 => Ok(artifacts.remove(0)),
+        n => Err(BuildError::ExpectedOneArtifact(n)),
     }
 }
 
+/// Which executables to build
+#[derive(Debug, Clone)]
+enum Selection {
+    /// Needs `cargo metadata` to resolve into [`Targets::PackageBin`]
+    DefaultBin,
+    Targets(Targets),
+}
+
+/// Executables we can ask `cargo build` for directly
+#[derive(Debug, Clone)]
+enum Targets {
+    Bins(Vec<String>),
+    Examples(Vec<String>),
+    AllBins,
+    AllExamples,
+    /// One binary of one package
+    PackageBin(PackageId, String),
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::test_common::{init, Result};
+    use crate::ProgressKind;
+    use crate::{
+        test_common::{has_toolchain, host_triple, init, Result},
+        CancellationToken, CargoSpec, DiagnosticLevel, FeatureSpec, ProfileSpec, UnstableFlag,
+    };
     use pretty_assertions::{assert_eq, assert_ne};
+    use std::{
+        env, fs, mem,
+        path::PathBuf,
+        sync::{Arc, Mutex},
+        thread,
+        time::{Duration, Instant},
+    };
 
     // TODO: Use assert_matches! when stable
 
@@ -244,6 +413,35 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_profile_named() -> Result {
+        init();
+        let artifact = Compiler::bin("hello_world")
+            .workspace("samples/hello_world")
+            .profile(ProfileSpec::named("dist"))
+            .compile()?;
+        assert_eq!("dist", artifact.profile_name);
+        assert_ne!("0", artifact.profile.opt_level);
+        assert!(artifact
+            .executable
+            .parent()
+            .unwrap()
+            .ends_with("target/dist"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_profile_release_override() -> Result {
+        init();
+        let artifact = Compiler::bin("hello_world")
+            .workspace("samples/hello_world")
+            .profile(ProfileSpec::named("dist"))
+            .release(true)
+            .compile()?;
+        assert_eq!("release", artifact.profile_name);
+        Ok(())
+    }
+
     #[test]
     fn test_release_default() -> Result {
         init();
@@ -251,9 +449,545 @@ mod tests {
             .workspace("samples/hello_world")
             .compile()?;
         assert_eq!("0", artifact.profile.opt_level);
+        assert_eq!("dev", artifact.profile_name);
+        Ok(())
+    }
+
+    #[test]
+    fn test_config() -> Result {
+        init();
+        let artifact = Compiler::bin("hello_world")
+            .workspace("samples/hello_world")
+            .config("profile.dev.opt-level", 1)
+            .compile()?;
+        assert_eq!("1", artifact.profile.opt_level);
+        Ok(())
+    }
+
+    #[test]
+    fn test_env() -> Result {
+        init();
+        let artifact = Compiler::bin("hello_world")
+            .workspace("samples/hello_world")
+            .env("CARGO_BUILD_TARGET_DIR", "target/seacan_test_env")
+            .compile()?;
+        assert!(artifact
+            .executable
+            .parent()
+            .unwrap()
+            .ends_with("target/seacan_test_env/debug"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_target_triple() -> Result {
+        init();
+        let triple = host_triple();
+        let triple = triple.as_str();
+        let artifact = Compiler::bin("hello_world")
+            .workspace("samples/hello_world")
+            .target(triple)
+            .compile()?;
+        assert_eq!(Some(triple), artifact.target_triple.as_deref());
+        assert!(artifact
+            .executable
+            .ancestors()
+            .any(|dir| dir.file_name() == Some(triple)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_target_triple_default() -> Result {
+        init();
+        let artifact = Compiler::bin("hello_world")
+            .workspace("samples/hello_world")
+            .compile()?;
+        assert_eq!(None, artifact.target_triple);
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_script() -> Result {
+        init();
+        let artifact = Compiler::bin("build_script")
+            .workspace("samples/build_script")
+            .compile()?;
+        let script = artifact.build_script.as_ref().unwrap();
+        assert!(script.out_dir.join("generated.txt").is_file());
+        assert_eq!(vec!["from_build_script".to_string()], script.cfgs);
+        let env = artifact.build_script_env();
+        assert!(env.contains(&("OUT_DIR".to_string(), script.out_dir.to_string())));
+        assert!(env.contains(&(
+            "BUILD_SCRIPT_VALUE".to_string(),
+            "from build script".to_string()
+        )));
+        Ok(())
+    }
+
+    #[test]
+    fn test_no_build_script() -> Result {
+        init();
+        let artifact = Compiler::bin("hello_world")
+            .workspace("samples/hello_world")
+            .compile()?;
+        assert_eq!(None, artifact.build_script);
+        assert!(artifact.build_script_env().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_timings() -> Result {
+        init();
+        let outcome = Compiler::bin("build_script")
+            .workspace("samples/build_script")
+            .timings(true)
+            .compile_outcome()?;
+        let timings = outcome.timings.unwrap();
+        assert!(timings.report.is_file());
+        assert!(!timings.concurrency.is_empty());
+        let unit = timings
+            .units
+            .iter()
+            .find(|unit| unit.target == "build-script")
+            .unwrap();
+        assert_eq!("build_script", unit.package);
+        assert!(timings
+            .units
+            .iter()
+            .any(|unit| unit.target == r#"build_script "bin""#));
+        Ok(())
+    }
+
+    #[test]
+    fn test_timings_default() -> Result {
+        init();
+        let outcome = Compiler::bin("hello_world")
+            .workspace("samples/hello_world")
+            .compile_outcome()?;
+        assert_eq!(None, outcome.timings);
+        Ok(())
+    }
+
+    #[test]
+    fn test_outcome() -> Result {
+        init();
+        let mut compiler = Compiler::bin("hello_world");
+        compiler
+            .workspace("samples/hello_world")
+            .target_dir("target/seacan_test_outcome");
+
+        let first = compiler.compile_outcome()?;
+        assert_eq!(Some(true), first.finished.as_ref().map(|f| f.success));
+        assert!(first.warning_count() > 0);
+        let (package, levels) = first.diagnostics.iter().next().unwrap();
+        assert_eq!(&first.artifacts[0].package_id, package);
+        assert!(levels.contains_key(&DiagnosticLevel::Warning));
+
+        let second = compiler.compile_outcome()?;
+        assert_eq!(0, second.rebuilt);
+        assert!(second.fresh > 0);
+        assert_eq!(first.warning_count(), second.warning_count());
+        Ok(())
+    }
+
+    #[test]
+    fn test_progress() -> Result {
+        init();
+        let _ = fs::remove_dir_all("samples/build_script/target/seacan_test_progress");
+        let progress = Arc::new(Mutex::new(Vec::new()));
+        let mut compiler = Compiler::bin("build_script");
+        compiler
+            .workspace("samples/build_script")
+            .target_dir("target/seacan_test_progress")
+            .on_progress({
+                let progress = Arc::clone(&progress);
+                move |p| progress.lock().unwrap().push(p)
+            });
+
+        compiler.compile()?;
+        let first = mem::take(&mut *progress.lock().unwrap());
+        let kinds: Vec<_> = first.iter().map(|p| p.kind).collect();
+        assert_eq!(
+            vec![
+                ProgressKind::Started,
+                ProgressKind::Finished,
+                ProgressKind::BuildScriptRan,
+                ProgressKind::Finished,
+            ],
+            kinds
+        );
+        assert!(first.iter().all(|p| p.package == "build_script"));
+        assert_eq!(
+            vec![0, 1, 2, 3],
+            first.iter().map(|p| p.completed).collect::<Vec<_>>()
+        );
+        assert!(first
+            .iter()
+            .all(|p| p.total.map_or(true, |total| total == 3)));
+        assert_eq!(
+            Some("build_script"),
+            first
+                .last()
+                .unwrap()
+                .target
+                .as_ref()
+                .map(|t| t.name.as_str())
+        );
+
+        compiler.compile()?;
+        let second = mem::take(&mut *progress.lock().unwrap());
+        assert!(second
+            .iter()
+            .all(|p| matches!(p.kind, ProgressKind::Fresh | ProgressKind::BuildScriptRan)));
+        assert_eq!(3, second.last().unwrap().completed);
+        Ok(())
+    }
+
+    #[test]
+    fn test_events() -> Result {
+        init();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        Compiler::bin("build_script")
+            .workspace("samples/build_script")
+            .on_progress(|_| {})
+            .on_event({
+                let events = Arc::clone(&events);
+                move |event| events.lock().unwrap().push(event)
+            })
+            .compile()?;
+
+        let events = events.lock().unwrap();
+        let has = |f: fn(&BuildEvent) -> bool| events.iter().any(f);
+        assert!(has(|e| matches!(e, BuildEvent::BuildScriptExecuted(_))));
+        assert!(has(|e| matches!(
+            e,
+            BuildEvent::CompilerArtifact(art) if art.target.kind == ["custom-build"]
+        )));
+        assert!(has(|e| matches!(e, BuildEvent::Progress(_))));
+        assert!(has(|e| matches!(
+            e,
+            BuildEvent::Stderr(line) if line.trim_start().starts_with("Finished")
+        )));
+        assert!(!has(|e| matches!(
+            e,
+            BuildEvent::Stderr(line) if line.contains("Building [")
+        )));
+        // Stderr can come after, but it's the last message on stdout
+        assert!(matches!(
+            events.iter().rfind(|e| !matches!(e, BuildEvent::Stderr(_))),
+            Some(BuildEvent::BuildFinished(finished)) if finished.success
+        ));
         Ok(())
     }
 
+    #[test]
+    fn test_on_thread() -> Result {
+        init();
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let mut compiler = Compiler::bin("hello_world");
+        compiler.workspace("samples/hello_world").on_compiler_msg({
+            let messages = Arc::clone(&messages);
+            move |msg| messages.lock().unwrap().push(msg)
+        });
+
+        let artifact = thread::spawn(move || compiler.compile()).join().unwrap()?;
+        assert_eq!("hello_world", artifact.target.name);
+        assert!(!messages.lock().unwrap().is_empty());
+        Ok(())
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_compile_async() -> Result {
+        init();
+        let artifact = Compiler::bin("hello_world")
+            .workspace("samples/hello_world")
+            .compile_async()
+            .await?;
+        assert_eq!("hello_world", artifact.target.name);
+        Ok(())
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_compile_async_spawned() -> Result {
+        init();
+        let mut compiler = Compiler::bin("hello_world");
+        compiler.workspace("samples/hello_world");
+        let artifact = tokio::spawn(async move { compiler.compile_async().await }).await??;
+        assert_eq!("hello_world", artifact.target.name);
+        Ok(())
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_compile_async_default_bin() -> Result {
+        init();
+        let artifact = Compiler::default_bin()
+            .workspace("samples/default_run")
+            .compile_async()
+            .await?;
+        assert_eq!("second", artifact.target.name);
+        Ok(())
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_compile_async_nonexistent() {
+        init();
+        let result = Compiler::bin("bin_does_not_exist")
+            .workspace("samples/hello_world")
+            .compile_async()
+            .await;
+        assert!(matches!(result, Err(BuildError::NotFound(_))));
+    }
+
+    #[test]
+    fn test_cancelled_before_start() {
+        init();
+        let token = CancellationToken::new();
+        token.cancel();
+        let result = Compiler::bin("hello_world")
+            .workspace("samples/hello_world")
+            .cancellation_token(token)
+            .compile();
+        assert!(matches!(result, Err(BuildError::Cancelled)));
+    }
+
+    #[test]
+    fn test_cancel() {
+        init();
+        let pid_file = pid_file("cancel");
+        let token = CancellationToken::new();
+        let handle = {
+            let token = token.clone();
+            let pid_file = pid_file.clone();
+            thread::spawn(move || {
+                Compiler::bin("slow")
+                    .workspace("samples/slow")
+                    .target_dir("target/seacan_test_cancel")
+                    .env("SEACAN_BUILD_SCRIPT_PID", pid_file)
+                    .cancellation_token(token)
+                    .compile()
+            })
+        };
+
+        let start = Instant::now();
+        let build_script = loop {
+            if let Some(pid) = fs::read_to_string(&pid_file)
+                .ok()
+                .and_then(|pid| pid.parse::<u32>().ok())
+            {
+                break pid;
+            }
+            assert!(
+                start.elapsed() < Duration::from_secs(60),
+                "build script never started"
+            );
+            thread::sleep(Duration::from_millis(50));
+        };
+
+        token.cancel();
+        let result = handle.join().unwrap();
+        assert!(matches!(result, Err(BuildError::Cancelled)), "{:?}", result);
+        assert!(start.elapsed() < Duration::from_secs(30));
+
+        #[cfg(target_os = "linux")]
+        {
+            let killed = Instant::now();
+            while is_running(build_script) {
+                assert!(
+                    killed.elapsed() < Duration::from_secs(5),
+                    "build script still running"
+                );
+                thread::sleep(Duration::from_millis(50));
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = build_script;
+    }
+
+    #[test]
+    fn test_timeout() {
+        init();
+        let timeout = Duration::from_secs(5);
+        let start = Instant::now();
+        let result = Compiler::bin("slow")
+            .workspace("samples/slow")
+            .target_dir("target/seacan_test_timeout")
+            .env("SEACAN_BUILD_SCRIPT_PID", pid_file("timeout"))
+            .timeout(timeout)
+            .compile();
+        assert!(
+            matches!(result, Err(BuildError::TimedOut(actual)) if actual == timeout),
+            "{:?}",
+            result
+        );
+        assert!(start.elapsed() < Duration::from_secs(30));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_timeout_async() {
+        init();
+        let timeout = Duration::from_secs(5);
+        let result = Compiler::bin("slow")
+            .workspace("samples/slow")
+            .target_dir("target/seacan_test_timeout_async")
+            .env("SEACAN_BUILD_SCRIPT_PID", pid_file("timeout_async"))
+            .timeout(timeout)
+            .compile_async()
+            .await;
+        assert!(
+            matches!(result, Err(BuildError::TimedOut(actual)) if actual == timeout),
+            "{:?}",
+            result
+        );
+    }
+
+    /// Where the slow sample's build script should write its PID
+    fn pid_file(test: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("seacan_test_{test}_{}.pid", std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    /// If a process exists and isn't a zombie
+    #[cfg(target_os = "linux")]
+    fn is_running(pid: u32) -> bool {
+        fs::read_to_string(format!("/proc/{pid}/stat")).is_ok_and(|stat| {
+            // The state comes after the executable name, which is in parentheses
+            stat.rsplit(')')
+                .next()
+                .and_then(|rest| rest.split_whitespace().next())
+                != Some("Z")
+        })
+    }
+
+    #[test]
+    fn test_cargo_path() -> Result {
+        init();
+        let artifact = Compiler::bin("hello_world")
+            .workspace("samples/hello_world")
+            .cargo(CargoSpec::path(env!("CARGO")))
+            .compile()?;
+        assert_eq!("hello_world", artifact.target.name);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cargo_path_nonexistent() {
+        init();
+        let result = Compiler::bin("hello_world")
+            .workspace("samples/hello_world")
+            .cargo(CargoSpec::path("/nonexistent/cargo"))
+            .compile();
+        assert!(matches!(result, Err(BuildError::RunCargo(_))));
+    }
+
+    #[test]
+    fn test_toolchain() -> Result {
+        init();
+        if !has_toolchain("stable") {
+            return Ok(());
+        }
+        let artifact = Compiler::bin("hello_world")
+            .workspace("samples/hello_world")
+            .cargo(CargoSpec::toolchain("stable"))
+            .compile()?;
+        assert_eq!("hello_world", artifact.target.name);
+        Ok(())
+    }
+
+    #[test]
+    fn test_jobs_keep_going() -> Result {
+        init();
+        let artifact = Compiler::bin("hello_world")
+            .workspace("samples/hello_world")
+            .jobs(1)
+            .keep_going(true)
+            .compile()?;
+        assert_eq!("hello_world", artifact.target.name);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unstable_rejected() {
+        init();
+        if !has_toolchain("stable") {
+            return;
+        }
+        let result = Compiler::bin("hello_world")
+            .workspace("samples/hello_world")
+            .cargo(CargoSpec::toolchain("stable"))
+            .unstable(UnstableFlag::UnstableOptions)
+            .compile();
+        assert!(matches!(result, Err(BuildError::UnstableRejected(_))));
+    }
+
+    #[test]
+    fn test_unstable_rustc_bootstrap() -> Result {
+        init();
+        if !has_toolchain("stable") {
+            return Ok(());
+        }
+        let artifact = Compiler::bin("hello_world")
+            .workspace("samples/hello_world")
+            .cargo(CargoSpec::toolchain("stable"))
+            .unstable(UnstableFlag::UnstableOptions)
+            .rustc_bootstrap(true)
+            .compile()?;
+        assert_eq!("hello_world", artifact.target.name);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unstable_unknown() {
+        init();
+        let result = Compiler::bin("hello_world")
+            .workspace("samples/hello_world")
+            .unstable(UnstableFlag::other("flag-that-doesnt-exist"))
+            .rustc_bootstrap(true)
+            .compile();
+        assert!(matches!(
+            result,
+            Err(BuildError::UnstableRejected(msg)) if msg.contains("flag-that-doesnt-exist")
+        ));
+    }
+
+    #[test]
+    fn test_locked() {
+        init();
+        let result = Compiler::bin("locked")
+            .workspace("samples/locked")
+            .locked(true)
+            .compile();
+        assert!(matches!(
+            result,
+            Err(BuildError::LockfileOutdated(path)) if path.ends_with("samples/locked/Cargo.lock")
+        ));
+    }
+
+    #[test]
+    fn test_frozen() {
+        init();
+        let result = Compiler::bin("locked")
+            .workspace("samples/locked")
+            .frozen(true)
+            .compile();
+        assert!(matches!(result, Err(BuildError::LockfileOutdated(_))));
+    }
+
+    #[test]
+    fn test_offline() {
+        init();
+        let result = Compiler::bin("offline")
+            .workspace("samples/offline")
+            .offline(true)
+            .compile();
+        assert!(matches!(result, Err(BuildError::Offline(_))));
+    }
+
     #[test]
     fn test_cargo_error() {
         init();
@@ -315,6 +1049,133 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_bins() -> Result {
+        init();
+        let mut artifacts = Compiler::bins(vec!["hello_world", "bin_2"])
+            .workspace("samples/hello_world")
+            .compile_all()?;
+        artifacts.sort_by(|a, b| a.target.name.cmp(&b.target.name));
+        let names: Vec<_> = artifacts.iter().map(|a| a.target.name.as_str()).collect();
+        assert_eq!(vec!["bin_2", "hello_world"], names);
+        Ok(())
+    }
+
+    #[test]
+    fn test_all_bins() -> Result {
+        init();
+        let mut artifacts = Compiler::all_bins()
+            .workspace("samples/hello_world")
+            .compile_all()?;
+        artifacts.sort_by(|a, b| a.target.name.cmp(&b.target.name));
+        let names: Vec<_> = artifacts.iter().map(|a| a.target.name.as_str()).collect();
+        assert_eq!(vec!["bin_2", "hello_world", "ws_member"], names);
+        Ok(())
+    }
+
+    #[test]
+    fn test_compile_expects_one_artifact() {
+        init();
+        let result = Compiler::all_bins()
+            .workspace("samples/hello_world")
+            .compile();
+        match result {
+            Err(BuildError::ExpectedOneArtifact(3)) => {}
+            other => panic!("Expected ExpectedOneArtifact, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_all_examples() -> Result {
+        init();
+        let mut artifacts = Compiler::all_examples()
+            .workspace("samples/hello_world")
+            .compile_all()?;
+        artifacts.sort_by(|a, b| a.target.name.cmp(&b.target.name));
+        let names: Vec<_> = artifacts.iter().map(|a| a.target.name.as_str()).collect();
+        assert_eq!(vec!["example_1", "example_2"], names);
+        Ok(())
+    }
+
+    #[test]
+    fn test_default_bin_package_name() -> Result {
+        init();
+        let artifact = Compiler::default_bin()
+            .workspace("samples/hello_world")
+            .compile()?;
+        assert_eq!("hello_world", artifact.target.name);
+        Ok(())
+    }
+
+    #[test]
+    fn test_default_bin_only_bin() -> Result {
+        init();
+        let artifact = Compiler::default_bin()
+            .workspace("samples/hello_world")
+            .package(PackageSpec::name("ws_member"))
+            .compile()?;
+        assert_eq!("ws_member", artifact.target.name);
+        Ok(())
+    }
+
+    #[test]
+    fn test_default_bin_current_package() -> Result {
+        init();
+        let artifact = Compiler::default_bin()
+            .workspace("samples/hello_world/ws_member")
+            .compile()?;
+        assert_eq!("ws_member", artifact.target.name);
+        Ok(())
+    }
+
+    #[test]
+    fn test_default_bin_default_run() -> Result {
+        init();
+        let artifact = Compiler::default_bin()
+            .workspace("samples/default_run")
+            .compile()?;
+        assert_eq!("second", artifact.target.name);
+        assert!(!artifact.package_id.repr.contains("ambiguous"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_default_bin_ambiguous() {
+        init();
+        let result = Compiler::default_bin()
+            .workspace("samples/default_run")
+            .package(PackageSpec::name("ambiguous"))
+            .compile();
+        match result {
+            Err(BuildError::NoDefaultBin { mut candidates }) => {
+                candidates.sort();
+                assert_eq!(vec!["first", "second"], candidates);
+            }
+            other => panic!("Expected NoDefaultBin, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_default_bin_versioned_package() -> Result {
+        init();
+        let artifact = Compiler::default_bin()
+            .workspace("samples/hello_world")
+            .package(PackageSpec::name("ws_member@0.1.0"))
+            .compile()?;
+        assert_eq!("ws_member", artifact.target.name);
+        Ok(())
+    }
+
+    #[test]
+    fn test_default_bin_nonexistent_package() {
+        init();
+        let result = Compiler::default_bin()
+            .workspace("samples/hello_world")
+            .package(PackageSpec::name("nonexistent_package"))
+            .compile();
+        assert!(matches!(result, Err(BuildError::PackageNotFound(_))));
+    }
+
     #[test]
     fn test_example_nonexistent() {
         init();
@@ -334,6 +1195,28 @@ mod tests {
         assert!(matches!(result, Err(BuildError::PackageNotFound(_))));
     }
 
+    #[test]
+    fn test_manifest_path() -> Result {
+        init();
+        let artifact = Compiler::bin("ws_member")
+            .manifest_path("samples/hello_world/ws_member/Cargo.toml")
+            .compile()?;
+        assert_eq!("ws_member", artifact.target.name);
+        assert!(artifact.target.src_path.ends_with("ws_member/src/main.rs"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_manifest_path_in_workspace() -> Result {
+        init();
+        let artifact = Compiler::bin("hello_world")
+            .workspace("samples")
+            .manifest_path("hello_world/Cargo.toml")
+            .compile()?;
+        assert_eq!("hello_world", artifact.target.name);
+        Ok(())
+    }
+
     #[test]
     fn test_ws_member_main() -> Result {
         init();
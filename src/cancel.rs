@@ -0,0 +1,400 @@
+//! Stopping a compile early, either because the user asked or because it took
+//! too long
+
+use std::{
+    io,
+    process::{Child, Command, ExitStatus},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, warn};
+
+use crate::BuildError;
+
+/// How often we check if we should stop when nothing else wakes us
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Cancels the compiles it's given to.
+///
+/// Clones share the same state, so keep one and give a clone to the compiler.
+/// Once cancelled a token stays cancelled.
+///
+/// ```no_run
+/// # use seacan::{bin::Compiler, BuildError, CancellationToken};
+/// let token = CancellationToken::new();
+/// let handle = {
+///     let token = token.clone();
+///     std::thread::spawn(move || {
+///         Compiler::bin("hello_world")
+///             .workspace("samples/hello_world")
+///             .cancellation_token(token)
+///             .compile()
+///     })
+/// };
+/// token.cancel();
+/// assert!(matches!(handle.join().unwrap(), Err(BuildError::Cancelled)));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    /// A token that hasn't been cancelled
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stop every compile using this token. Cargo and anything it started are
+    /// killed, and the compile returns [`BuildError::Cancelled`].
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// If [`Self::cancel`] has been called
+    #[must_use]
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// When one call to `compile` should stop
+#[derive(Debug, Clone)]
+pub(crate) struct Deadline {
+    token: Option<CancellationToken>,
+    /// When we started and how long we have
+    timeout: Option<(Instant, Duration)>,
+}
+
+impl Deadline {
+    /// Starts the timeout now
+    pub(crate) fn new(token: Option<CancellationToken>, timeout: Option<Duration>) -> Self {
+        Self {
+            token,
+            timeout: timeout.map(|timeout| (Instant::now(), timeout)),
+        }
+    }
+
+    /// A deadline for part of the work, which also stops `timeout` from now if
+    /// that's sooner
+    pub(crate) fn limit(&self, timeout: Option<Duration>) -> Self {
+        let now = Instant::now();
+        let timeout = match (self.timeout, timeout) {
+            (Some((start, outer)), Some(inner)) if start + outer <= now + inner => {
+                Some((start, outer))
+            }
+            (outer, None) => outer,
+            (_, Some(inner)) => Some((now, inner)),
+        };
+        Self {
+            token: self.token.clone(),
+            timeout,
+        }
+    }
+
+    fn is_unbounded(&self) -> bool {
+        self.token.is_none() && self.timeout.is_none()
+    }
+
+    /// `Err` if we should stop
+    pub(crate) fn check(&self) -> Result<(), BuildError> {
+        if self
+            .token
+            .as_ref()
+            .is_some_and(CancellationToken::is_cancelled)
+        {
+            return Err(BuildError::Cancelled);
+        }
+        match self.timeout {
+            Some((start, timeout)) if start.elapsed() >= timeout => {
+                Err(BuildError::TimedOut(timeout))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Check we haven't already stopped, and set up `cmd` so that
+    /// [`Self::watch`] can kill it and everything it starts.
+    pub(crate) fn prepare(&self, cmd: &mut Command) -> Result<(), BuildError> {
+        self.check()?;
+
+        #[cfg(unix)]
+        if !self.is_unbounded() {
+            use std::os::unix::process::CommandExt;
+            // So that we can kill the whole tree (e.g. rustc and build scripts)
+            // by killing the group. This also means a Ctrl-C in the terminal
+            // won't reach it, so we only do it when we might need to.
+            cmd.process_group(0);
+        }
+        #[cfg(not(unix))]
+        let _ = cmd;
+
+        Ok(())
+    }
+
+    /// Kill the process tree rooted at `child` once we should stop. The
+    /// process must have been started from a command passed to
+    /// [`Self::prepare`], and must be waited for with [`Watchdog::wait`].
+    pub(crate) fn watch(&self, child: &Child) -> Watchdog {
+        let state = Arc::new(Mutex::new(WatchState::Watching));
+        if self.is_unbounded() {
+            return Watchdog {
+                deadline: self.clone(),
+                thread: None,
+                state,
+            };
+        }
+
+        let pid = child.id();
+        let thread = thread::spawn({
+            let deadline = self.clone();
+            let state = Arc::clone(&state);
+            move || loop {
+                // Hold the lock while we kill so that the child can't be
+                // reaped (freeing its ID for reuse) at the same time
+                let mut state = lock(&state);
+                if *state != WatchState::Watching {
+                    return;
+                }
+                if let Err(err) = deadline.check() {
+                    debug!(pid, %err, "Killing process tree");
+                    kill_tree(pid);
+                    *state = WatchState::Killed;
+                    return;
+                }
+                drop(state);
+                thread::park_timeout(deadline.until_next_check());
+            }
+        });
+
+        Watchdog {
+            deadline: self.clone(),
+            thread: Some(thread),
+            state,
+        }
+    }
+
+    /// The async version of [`Self::watch`]: once we should stop, kill the
+    /// process tree rooted at `pid` and return why.
+    ///
+    /// Never returns if the deadline is unbounded or there's no `pid` (the
+    /// process has already exited). Race it against waiting for the process,
+    /// preferring the wait, so that we only kill a process that hasn't been
+    /// reaped yet.
+    #[cfg(feature = "tokio")]
+    pub(crate) async fn kill_when_stopped(&self, pid: Option<u32>) -> BuildError {
+        let pid = match pid {
+            Some(pid) if !self.is_unbounded() => pid,
+            _ => return std::future::pending().await,
+        };
+        loop {
+            if let Err(err) = self.check() {
+                debug!(pid, %err, "Killing process tree");
+                kill_tree(pid);
+                return err;
+            }
+            tokio::time::sleep(self.until_next_check()).await;
+        }
+    }
+
+    fn until_next_check(&self) -> Duration {
+        match self.timeout {
+            Some((start, timeout)) => timeout.saturating_sub(start.elapsed()).min(POLL_INTERVAL),
+            None => POLL_INTERVAL,
+        }
+    }
+}
+
+/// What a [`Watchdog`] has done so far
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WatchState {
+    Watching,
+    /// The process exited (and has been reaped), or we stopped watching
+    Stopped,
+    /// We killed the process
+    Killed,
+}
+
+/// Watches a process for [`Deadline::watch`]. Stops watching when dropped.
+#[derive(Debug)]
+pub(crate) struct Watchdog {
+    deadline: Deadline,
+    thread: Option<JoinHandle<()>>,
+    state: Arc<Mutex<WatchState>>,
+}
+
+impl Watchdog {
+    /// Wait for `child` to exit, like [`Child::wait`]. Once it has, we won't
+    /// kill it.
+    pub(crate) fn wait(&self, child: &mut Child) -> io::Result<ExitStatus> {
+        if self.thread.is_none() {
+            return child.wait();
+        }
+
+        // We poll rather than block so that we can reap the child and stop
+        // watching it in one step. Most test binaries exit quickly, so start
+        // by polling often.
+        let mut interval = Duration::from_millis(1);
+        loop {
+            let mut state = lock(&self.state);
+            if *state == WatchState::Killed {
+                drop(state);
+                return child.wait();
+            }
+            if let Some(status) = child.try_wait()? {
+                *state = WatchState::Stopped;
+                return Ok(status);
+            }
+            drop(state);
+            thread::sleep(interval);
+            interval = (interval * 2).min(POLL_INTERVAL);
+        }
+    }
+
+    /// Stop watching. `Err` if we killed the process before it exited, in
+    /// which case the caller should ignore how it exited.
+    pub(crate) fn finish(mut self) -> Result<(), BuildError> {
+        if self.stop() {
+            // If it was cancelled and timed out we report whichever comes
+            // first here, which is fine
+            Err(self.deadline.check().err().unwrap_or(BuildError::Cancelled))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Stop watching, returning if we killed the process
+    fn stop(&mut self) -> bool {
+        let killed = {
+            let mut state = lock(&self.state);
+            if *state == WatchState::Watching {
+                *state = WatchState::Stopped;
+            }
+            *state == WatchState::Killed
+        };
+        if let Some(thread) = self.thread.take() {
+            // It's either already finished or about to notice we've stopped
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+        killed
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Lock `state`, even if the watchdog thread panicked holding it
+fn lock(state: &Mutex<WatchState>) -> MutexGuard<'_, WatchState> {
+    state
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+#[cfg(unix)]
+fn kill_tree(pid: u32) {
+    use std::convert::TryFrom;
+
+    let Ok(group) = libc::pid_t::try_from(pid) else {
+        return;
+    };
+    // SAFETY: kill has no memory safety requirements. `Deadline::prepare`
+    // made the process the leader of its own group, so this kills it and
+    // everything it started.
+    if unsafe { libc::kill(-group, libc::SIGKILL) } != 0 {
+        warn!(pid, err = %std::io::Error::last_os_error(), "Failed to kill process group");
+    }
+}
+
+#[cfg(windows)]
+fn kill_tree(pid: u32) {
+    use std::process::Stdio;
+
+    let status = Command::new("taskkill")
+        .args(["/T", "/F", "/PID", &pid.to_string()])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+    if !matches!(status, Ok(status) if status.success()) {
+        warn!(pid, ?status, "Failed to kill process tree");
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn kill_tree(pid: u32) {
+    warn!(
+        pid,
+        "Don't know how to kill a process tree on this platform"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Stdio;
+
+    fn spawn(deadline: &Deadline, mut cmd: Command) -> Child {
+        deadline.prepare(&mut cmd).unwrap();
+        cmd.spawn().unwrap()
+    }
+
+    fn cargo_version(deadline: &Deadline) -> Child {
+        let mut cmd = Command::new("cargo");
+        cmd.arg("--version").stdout(Stdio::null());
+        spawn(deadline, cmd)
+    }
+
+    /// rustc waiting for us to write the source to its stdin
+    fn waits_for_stdin(deadline: &Deadline) -> Child {
+        let mut cmd = Command::new("rustc");
+        cmd.args(["-", "--emit=metadata"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        spawn(deadline, cmd)
+    }
+
+    #[test]
+    fn test_exited_before_deadline() {
+        let deadline = Deadline::new(None, Some(Duration::from_millis(50)));
+        let mut child = cargo_version(&deadline);
+        let watchdog = deadline.watch(&child);
+        assert!(watchdog.wait(&mut child).unwrap().success());
+
+        // Once it's been reaped the deadline passing doesn't matter
+        thread::sleep(Duration::from_millis(100));
+        assert!(deadline.check().is_err());
+        assert!(watchdog.finish().is_ok());
+    }
+
+    #[test]
+    fn test_killed() {
+        let token = CancellationToken::new();
+        let deadline = Deadline::new(Some(token.clone()), None);
+        let mut child = waits_for_stdin(&deadline);
+        let watchdog = deadline.watch(&child);
+        token.cancel();
+        let _ = watchdog.wait(&mut child).unwrap();
+        assert!(matches!(watchdog.finish(), Err(BuildError::Cancelled)));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_kill_when_stopped() {
+        let token = CancellationToken::new();
+        let deadline = Deadline::new(Some(token.clone()), None);
+        let mut child = waits_for_stdin(&deadline);
+        token.cancel();
+        let err = deadline.kill_when_stopped(Some(child.id())).await;
+        assert!(matches!(err, BuildError::Cancelled), "{:?}", err);
+        assert!(!child.wait().unwrap().success());
+    }
+}
@@ -0,0 +1,71 @@
+use cargo_metadata::{BuildFinished, BuildScript, CompilerMessage, Message};
+
+use crate::{progress::is_progress_bar, run::CargoOutput, CompilerArtifact, Progress};
+
+/// Something that happened during a build.
+///
+/// Unlike the rest of our API this isn't filtered down to what you asked to
+/// build, so it includes dependencies and artifacts that aren't executables.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum BuildEvent {
+    /// The compiler wants to display a message (e.g. a warning)
+    CompilerMessage(CompilerMessage),
+    /// A unit finished compiling or was found fresh
+    CompilerArtifact(CompilerArtifact),
+    /// A build script ran, or cargo reused its previous output
+    BuildScriptExecuted(BuildScript),
+    /// A line cargo printed to stdout that isn't a message (e.g. from a
+    /// proc macro)
+    TextLine(String),
+    /// A line cargo printed to stderr, e.g. its `Compiling` status lines,
+    /// `Blocking waiting for file lock`, or its own warnings. We leave out
+    /// the progress bar.
+    Stderr(String),
+    /// The build finished. This is always the last message on stdout, but
+    /// [`Self::Stderr`] lines can come after it.
+    BuildFinished(BuildFinished),
+    /// What we reported to the progress callback
+    Progress(Progress),
+}
+
+impl BuildEvent {
+    fn from_output(output: &CargoOutput) -> Option<Self> {
+        let event = match output {
+            CargoOutput::Message(msg) => match msg {
+                Message::CompilerMessage(msg) => Self::CompilerMessage(msg.clone()),
+                Message::CompilerArtifact(art) => Self::CompilerArtifact(art.clone()),
+                Message::BuildScriptExecuted(script) => Self::BuildScriptExecuted(script.clone()),
+                Message::TextLine(line) => Self::TextLine(line.clone()),
+                Message::BuildFinished(finished) => Self::BuildFinished(finished.clone()),
+                _ => return None,
+            },
+            CargoOutput::Stderr(line) if !is_progress_bar(line) => Self::Stderr(line.clone()),
+            CargoOutput::Stderr(_) => return None,
+        };
+        Some(event)
+    }
+}
+
+/// Report what cargo printed
+pub(crate) fn handle_output_event(
+    output: &CargoOutput,
+    cb: &mut Option<Box<dyn FnMut(BuildEvent) + Send>>,
+) {
+    // Only clone if someone's listening
+    if let Some(cb) = cb {
+        if let Some(event) = BuildEvent::from_output(output) {
+            cb(event);
+        }
+    }
+}
+
+/// Report progress we're about to give to the progress callback
+pub(crate) fn handle_progress_event(
+    progress: &Progress,
+    cb: &mut Option<Box<dyn FnMut(BuildEvent) + Send>>,
+) {
+    if let Some(cb) = cb {
+        cb(BuildEvent::Progress(progress.clone()));
+    }
+}
@@ -1,8 +1,11 @@
 #![warn(clippy::all, clippy::pedantic, missing_docs, clippy::cargo)]
+// Duplicate transitive deps are out of our control
+#![allow(clippy::multiple_crate_versions)]
 
 //! A library for interacting with cargo to build things.
 //!
-//! The main entrypoints are [`bin::Compiler`] and [`test::Compiler`].
+//! The main entrypoints are [`bin::Compiler`], [`library::Compiler`] and
+//! [`test::Compiler`].
 //!
 //! # Binaries and examples
 //!
@@ -25,13 +28,29 @@
 //!     package_id: PackageId { .. },
 //!     target: Target { .. },
 //!     profile: ArtifactProfile { .. },
+//!     profile_name: "dev",
+//!     target_triple: None,
 //!     features: [],
 //!     filenames: [ .. ],
 //!     executable: "/path/to/crate/.target/debug/example_name",
+//!     build_script: None,
 //!     fresh: true,
 //! })
 //! ```
 //!
+//! # Libraries
+//!
+//! Building a library gives you one artifact per crate type, each with the
+//! path to its `.so`, `.a`, `.rlib`, etc.
+//!
+//! ```
+//! # fn _w() -> eyre::Result<()> {
+//! use seacan::library;
+//! let library_artifacts = library::Compiler::lib().compile()?;
+//! # Ok(())
+//! # }
+//! ```
+//!
 //! # Tests
 //!
 //! Building tests is a bit more complicated. We expose all of Cargo's api for
@@ -66,6 +85,8 @@
 //!             TestFn {
 //!                 name: "test_frobs_baz",
 //!                 test_type: TestType::Test,
+//!                 ignored: None,
+//!                 ignore_reason: None,
 //!             },
 //!         ],
 //!     },
@@ -84,6 +105,42 @@
 //!
 //! Only the default test runner (`libtest`) is supported.
 //!
+//! [`test::Runner`] runs the tests you compiled one at a time, reporting if each
+//! passed, failed, was ignored or timed out, with what it printed and how long
+//! it took.
+//!
+//! Listing and running tests can both use libtest's unstable JSON output
+//! (`libtest_json`) instead of parsing its text.
+//!
+//! # Diagnostics and timings
+//!
+//! Every compiler also has a `compile_outcome` that returns a
+//! [`BuildOutcome`]: the artifacts along with the diagnostics cargo reported,
+//! how many units were fresh and, if you asked for them, cargo's timings.
+//!
+//! # Workspaces
+//!
+//! [`workspace::Workspace`] lists the packages in a workspace and their
+//! targets, including the manifest keys that decide how to build them
+//! (`required-features`, `test` and `harness`). Each target can give you the
+//! compiler that builds it.
+//!
+//! # Async
+//!
+//! With the `tokio` feature every compiler also has a `compile_async` that
+//! doesn't block the thread while cargo runs. It needs a runtime with IO and
+//! time enabled.
+//!
+//! Every compiler (and its callbacks) is `Send`, so you can also move a build to
+//! another thread or spawn it as a task.
+//!
+//! # Cancelling
+//!
+//! Every compiler takes a [`CancellationToken`] and a timeout. When either
+//! fires we kill cargo, everything it started, and any test binary we're
+//! listing the tests of, then return [`BuildError::Cancelled`] or
+//! [`BuildError::TimedOut`].
+//!
 //! # Why the name?
 //!
 //! A Sea Can is another word for a shipping container. Shipping containers were
@@ -91,30 +148,158 @@
 
 /// Compile bins and examples (i.e. what you can `cargo run`)
 pub mod bin;
+/// Compile libraries (e.g. a `cdylib` to load as a plugin)
+pub mod library;
 /// Compile tests (unit tests in lib, doctests, integration tests, and unit
 /// tests in bins and examples)
 pub mod test;
+/// Find the packages in a workspace and what you can build in each
+pub mod workspace;
+
+mod cancel;
+mod event;
+mod libtest;
+mod metadata;
+mod options;
+mod progress;
+mod run;
+mod runner;
 #[cfg(test)]
 mod test_common;
+mod timings;
 
 use std::{
-    io::{self, Read},
-    process::ChildStderr,
+    collections::HashMap, env, fmt::Write, io, path::PathBuf, process::Command, time::Duration,
 };
 
 pub use camino::{Utf8Path, Utf8PathBuf};
+use cargo_metadata::Message;
 pub use cargo_metadata::{
     diagnostic::{Diagnostic, DiagnosticLevel},
-    ArtifactProfile, CompilerMessage, PackageId, Target,
+    Artifact as CompilerArtifact, ArtifactProfile, BuildFinished, BuildScript, CompilerMessage,
+    PackageId, Target,
 };
 use lazy_static::lazy_static;
 use regex::Regex;
 use tracing::{debug, info, instrument, warn};
 
+use run::BuildScripts;
+
+pub use cancel::CancellationToken;
+pub use event::BuildEvent;
+pub use progress::{Progress, ProgressKind};
+pub use timings::{BuildTimings, Concurrency, UnitTiming};
+
 /// Ensure the rendered field of JSON messages contains embedded ANSI color
 /// codes for respecting rustc's default color scheme.
 const MSG_FORMAT: &str = "--message-format=json-diagnostic-rendered-ansi";
 
+/// What a successful build produced, from a compiler's `compile_outcome`
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct BuildOutcome<T> {
+    /// The artifact or artifacts we built
+    pub artifacts: T,
+    /// Every diagnostic the compiler reported (including warnings cargo
+    /// replayed for fresh units), grouped by package and then level
+    pub diagnostics: HashMap<PackageId, HashMap<DiagnosticLevel, Vec<Diagnostic>>>,
+    /// The number of artifacts cargo didn't need to rebuild, including
+    /// dependencies
+    pub fresh: usize,
+    /// The number of artifacts cargo rebuilt, including dependencies
+    pub rebuilt: usize,
+    /// Cargo's final message, or `None` if it didn't send one
+    pub finished: Option<BuildFinished>,
+    /// How long each unit took, if you asked for timings
+    pub timings: Option<BuildTimings>,
+}
+
+impl<T> BuildOutcome<T> {
+    /// Every diagnostic of `level` across all packages
+    pub fn diagnostics_of(&self, level: DiagnosticLevel) -> impl Iterator<Item = &Diagnostic> {
+        self.diagnostics
+            .values()
+            .filter_map(move |levels| levels.get(&level))
+            .flatten()
+    }
+
+    /// The number of warnings across all packages
+    #[must_use]
+    pub fn warning_count(&self) -> usize {
+        self.diagnostics_of(DiagnosticLevel::Warning).count()
+    }
+}
+
+/// Everything but the artifacts of a [`BuildOutcome`], collected as cargo's
+/// messages come in
+#[derive(Debug, Default)]
+pub(crate) struct BuildSummary {
+    diagnostics: HashMap<PackageId, HashMap<DiagnosticLevel, Vec<Diagnostic>>>,
+    errors: Vec<CompileError>,
+    fresh: usize,
+    rebuilt: usize,
+    finished: Option<BuildFinished>,
+}
+
+impl BuildSummary {
+    pub(crate) fn record(&mut self, msg: &Message) {
+        match msg {
+            Message::CompilerMessage(msg) => {
+                if let DiagnosticLevel::Error | DiagnosticLevel::Ice = msg.message.level {
+                    self.errors.push(CompileError {
+                        package_id: msg.package_id.clone(),
+                        target: msg.target.clone(),
+                        diagnostic: msg.message.clone(),
+                    });
+                }
+                self.diagnostics
+                    .entry(msg.package_id.clone())
+                    .or_default()
+                    .entry(msg.message.level)
+                    .or_default()
+                    .push(msg.message.clone());
+            }
+            Message::CompilerArtifact(art) if art.fresh => self.fresh += 1,
+            Message::CompilerArtifact(_) => self.rebuilt += 1,
+            Message::BuildFinished(finished) => self.finished = Some(finished.clone()),
+            _ => {}
+        }
+    }
+
+    /// Why the build failed, preferring the errors the compiler reported over
+    /// what we can get from stderr
+    pub(crate) fn error(&self, stderr: String) -> BuildError {
+        if self.errors.is_empty() {
+            BuildError::from_stderr_buf(stderr)
+        } else {
+            BuildError::Compile {
+                errors: self.errors.clone(),
+                stderr,
+            }
+        }
+    }
+
+    /// The error-level diagnostics the compiler reported
+    pub(crate) fn into_errors(self) -> Vec<CompileError> {
+        self.errors
+    }
+
+    pub(crate) fn into_outcome<T>(
+        self,
+        artifacts: T,
+        timings: Option<BuildTimings>,
+    ) -> BuildOutcome<T> {
+        BuildOutcome {
+            artifacts,
+            diagnostics: self.diagnostics,
+            fresh: self.fresh,
+            rebuilt: self.rebuilt,
+            finished: self.finished,
+            timings,
+        }
+    }
+}
+
 /// Like [`cargo_metadata::Artifact`], but always has an executable
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 #[non_exhaustive]
@@ -125,6 +310,12 @@ pub struct ExecutableArtifact {
     pub target: Target,
     /// The profile this artifact was compiled with
     pub profile: ArtifactProfile,
+    /// The name of the profile this artifact was compiled with (e.g. `dev`,
+    /// `release`, or a custom profile)
+    pub profile_name: String,
+    /// The target triple this artifact was compiled for (i.e. `--target`), or
+    /// `None` if it was compiled for the host
+    pub target_triple: Option<String>,
     /// The enabled features for this artifact
     pub features: Vec<String>,
     /// The full paths to the generated artifacts
@@ -132,12 +323,21 @@ pub struct ExecutableArtifact {
     pub filenames: Vec<Utf8PathBuf>,
     /// Path to the executable file
     pub executable: Utf8PathBuf,
+    /// The output of the package's build script, if it has one, from the run
+    /// this artifact was built with
+    pub build_script: Option<BuildScript>,
     /// If true, then the files were already generated
     pub fresh: bool,
 }
 
 impl ExecutableArtifact {
-    fn maybe_from(art: cargo_metadata::Artifact) -> Option<Self> {
+    fn maybe_from(
+        art: cargo_metadata::Artifact,
+        profile_name: String,
+        target_triple: Option<String>,
+        build_scripts: &BuildScripts,
+    ) -> Option<Self> {
+        let build_script = build_scripts.find(&art).cloned();
         let cargo_metadata::Artifact {
             package_id,
             target,
@@ -150,15 +350,34 @@ impl ExecutableArtifact {
         } = art;
 
         Some(Self {
+            build_script,
             package_id,
             target,
             profile,
+            profile_name,
+            target_triple,
             features,
             filenames,
             executable: executable?,
             fresh,
         })
     }
+
+    /// The environment variables from the build script that cargo sets when
+    /// it runs the executable (i.e. `OUT_DIR` and `cargo:rustc-env`).
+    #[must_use]
+    pub fn build_script_env(&self) -> Vec<(String, String)> {
+        build_script_env(self.build_script.as_ref())
+    }
+}
+
+fn build_script_env(build_script: Option<&BuildScript>) -> Vec<(String, String)> {
+    let mut env = Vec::new();
+    if let Some(script) = build_script {
+        env.push(("OUT_DIR".to_owned(), script.out_dir.to_string()));
+        env.extend(script.env.iter().cloned());
+    }
+    env
 }
 
 /// Describe a package (i.e. the `--package` flag)
@@ -166,7 +385,9 @@ impl ExecutableArtifact {
 pub enum PackageSpec {
     /// Any package in the workspace
     Any,
-    /// The name of a package in the workspace
+    /// The name of a package in the workspace. Like `--package`, this can
+    /// also be a glob of names (e.g. `ws_*`) or include a version (e.g.
+    /// `name@1.2`). Use [`Self::Id`] for a full package ID spec.
     Name(String),
     /// The full ID of a package in the workspace
     /// (i.e. `seacan 0.0.1 (path+file:///home/me/rdbg-proj/seacan)`).
@@ -264,7 +485,7 @@ impl FeatureSpec {
                 features.push(feature);
             }
             FeatureSpecInner::All => {
-                info!("Ignoring feature append as set to all")
+                info!("Ignoring feature append as set to all");
             }
         }
         self
@@ -291,16 +512,249 @@ impl FeatureSpec {
     }
 }
 
+/// Describe the profile to build with
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Default)]
+pub enum ProfileSpec {
+    /// Whatever cargo chooses by default (`dev` for builds, `test` for tests)
+    #[default]
+    Default,
+    /// The release profile (i.e. `--release`)
+    Release,
+    /// A built-in or custom profile by name (i.e. `--profile <name>`)
+    Named(String),
+}
+
+impl ProfileSpec {
+    /// Helper for [`Self::Named`]
+    pub fn named(name: impl Into<String>) -> Self {
+        Self::Named(name.into())
+    }
+
+    /// The name of the profile cargo will use, given the name of the profile
+    /// it uses by default for the command.
+    fn name(&self, default: &str) -> String {
+        match self {
+            Self::Default => default.to_owned(),
+            Self::Release => "release".to_owned(),
+            Self::Named(name) => name.clone(),
+        }
+    }
+
+    fn to_args(&self) -> Vec<String> {
+        match self {
+            Self::Default => Vec::new(),
+            Self::Release => vec!["--release".into()],
+            Self::Named(name) => vec!["--profile".into(), name.clone()],
+        }
+    }
+}
+
+/// Describe which cargo to run
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Default)]
+pub enum CargoSpec {
+    /// The cargo in the `CARGO` environment variable if it's set, otherwise
+    /// `cargo` from the `PATH`.
+    ///
+    /// Cargo sets `CARGO` when it runs subcommands, build scripts, and
+    /// binaries, so this uses the same cargo as whatever is running us.
+    #[default]
+    Default,
+    /// A rustup toolchain (i.e. `cargo +<toolchain>`).
+    ///
+    /// This runs the rustup proxy `cargo` from the `PATH` with
+    /// `RUSTUP_TOOLCHAIN` set.
+    Toolchain(String),
+    /// An explicit path to a cargo executable
+    Path(PathBuf),
+}
+
+impl CargoSpec {
+    /// Helper for [`Self::Toolchain`]
+    pub fn toolchain(toolchain: impl Into<String>) -> Self {
+        Self::Toolchain(toolchain.into())
+    }
+
+    /// Helper for [`Self::Path`]
+    pub fn path(path: impl Into<PathBuf>) -> Self {
+        Self::Path(path.into())
+    }
+
+    fn command(&self) -> Command {
+        match self {
+            Self::Default => Command::new(env::var_os("CARGO").unwrap_or_else(|| "cargo".into())),
+            Self::Toolchain(toolchain) => {
+                let mut cmd = Command::new("cargo");
+                cmd.env("RUSTUP_TOOLCHAIN", toolchain);
+                cmd
+            }
+            Self::Path(path) => Command::new(path),
+        }
+    }
+}
+
+/// An unstable cargo flag (i.e. `-Z <flag>`)
+///
+/// These are only accepted by nightly cargo, unless you opt in to
+/// `RUSTC_BOOTSTRAP` (see [`bin::Compiler::rustc_bootstrap`]). See
+/// <https://doc.rust-lang.org/cargo/reference/unstable.html>.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum UnstableFlag {
+    /// `-Z unstable-options`, which enables unstable command line options
+    UnstableOptions,
+    /// `-Z build-std=<crates>`, or every standard crate if empty
+    BuildStd(Vec<String>),
+    /// `-Z build-std-features=<features>`
+    BuildStdFeatures(Vec<String>),
+    /// Any other flag (`-Z <name>` or `-Z <name>=<value>`)
+    Other {
+        /// The name of the flag
+        name: String,
+        /// The value of the flag, if it takes one
+        value: Option<String>,
+    },
+}
+
+impl UnstableFlag {
+    /// Helper for [`Self::Other`] without a value
+    pub fn other(name: impl Into<String>) -> Self {
+        Self::Other {
+            name: name.into(),
+            value: None,
+        }
+    }
+
+    /// What you'd pass after `-Z`
+    fn to_arg(&self) -> String {
+        match self {
+            Self::UnstableOptions => "unstable-options".into(),
+            Self::BuildStd(crates) if crates.is_empty() => "build-std".into(),
+            Self::BuildStd(crates) => format!("build-std={}", crates.join(",")),
+            Self::BuildStdFeatures(features) => {
+                format!("build-std-features={}", features.join(","))
+            }
+            Self::Other { name, value: None } => name.clone(),
+            Self::Other {
+                name,
+                value: Some(value),
+            } => format!("{name}={value}"),
+        }
+    }
+}
+
+/// A value for a cargo configuration override (i.e. `--config KEY=VALUE`)
+///
+/// See <https://doc.rust-lang.org/cargo/reference/config.html> for the keys
+/// cargo understands.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum ConfigValue {
+    /// A string (e.g. `build.target-dir`)
+    String(String),
+    /// An integer (e.g. `profile.dev.debug`)
+    Integer(i64),
+    /// A boolean (e.g. `profile.dev.incremental`)
+    Bool(bool),
+    /// A list of strings (e.g. `build.rustflags`)
+    List(Vec<String>),
+}
+
+impl ConfigValue {
+    /// The value as TOML, which is what cargo expects after the `=`.
+    fn to_toml(&self) -> String {
+        match self {
+            Self::String(value) => toml_string(value),
+            Self::Integer(value) => value.to_string(),
+            Self::Bool(value) => value.to_string(),
+            Self::List(values) => {
+                let values: Vec<_> = values.iter().map(|value| toml_string(value)).collect();
+                format!("[{}]", values.join(", "))
+            }
+        }
+    }
+}
+
+impl From<String> for ConfigValue {
+    fn from(value: String) -> Self {
+        Self::String(value)
+    }
+}
+
+impl From<&str> for ConfigValue {
+    fn from(value: &str) -> Self {
+        Self::String(value.to_owned())
+    }
+}
+
+impl From<i64> for ConfigValue {
+    fn from(value: i64) -> Self {
+        Self::Integer(value)
+    }
+}
+
+impl From<i32> for ConfigValue {
+    fn from(value: i32) -> Self {
+        Self::Integer(value.into())
+    }
+}
+
+impl From<bool> for ConfigValue {
+    fn from(value: bool) -> Self {
+        Self::Bool(value)
+    }
+}
+
+impl From<Vec<String>> for ConfigValue {
+    fn from(values: Vec<String>) -> Self {
+        Self::List(values)
+    }
+}
+
+impl From<Vec<&str>> for ConfigValue {
+    fn from(values: Vec<&str>) -> Self {
+        Self::List(values.into_iter().map(str::to_owned).collect())
+    }
+}
+
+/// Quote as a TOML basic string
+fn toml_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => {
+                write!(out, "\\u{:04X}", u32::from(c)).expect("writing to a String can't fail");
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
 pub(crate) fn handle_compiler_msg(
     msg: CompilerMessage,
-    cb: &mut Option<Box<dyn FnMut(CompilerMessage)>>,
+    cb: &mut Option<Box<dyn FnMut(CompilerMessage) + Send>>,
 ) {
     debug!(?msg, "Got compiler message");
     if let Some(cb) = cb {
-        cb(msg)
+        cb(msg);
     }
 }
 
+/// An error-level diagnostic and the target it came from
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub struct CompileError {
+    /// The package the target is in
+    pub package_id: PackageId,
+    /// The target that failed to compile
+    pub target: Target,
+    /// What the compiler reported. [`Diagnostic::rendered`] has the message
+    /// as rustc would print it.
+    pub diagnostic: Diagnostic,
+}
+
 /// Failed to build
 #[derive(Debug, thiserror::Error, displaydoc::Display)]
 pub enum BuildError {
@@ -310,18 +764,43 @@ pub enum BuildError {
     NotFound(String),
     /// Package ID specification `{0:?}` did not match any packages
     PackageNotFound(String),
+    /// The lock file `{0}` needs to be created or updated, but `--locked` or `--frozen` prevents it
+    LockfileOutdated(String),
+    /// Cargo needed to access the network, but `--offline` or `--frozen` prevents it. Stderr: {0}
+    Offline(String),
+    /// The toolchain rejected an unstable flag: {0}
+    UnstableRejected(String),
+    /// Failed to parse the output of `cargo metadata`: {0}
+    Metadata(String),
+    /// Failed to read a `Cargo.toml`: {0}
+    Manifest(String),
+    /// Couldn't determine which binary `cargo run` would pick. Candidates: {candidates:?}
+    NoDefaultBin {
+        /// The binaries that could be the default, empty if there are none
+        candidates: Vec<String>,
+    },
+    /// Expected cargo to build exactly one executable, but it built {0}
+    ExpectedOneArtifact(usize),
+    /// Failed to read cargo's timing report: {0}
+    Timings(String),
+    /// Failed to compile, stderr: {stderr}
+    Compile {
+        /// The error-level diagnostics the compiler reported
+        errors: Vec<CompileError>,
+        /// What cargo wrote to stderr
+        stderr: String,
+    },
+    /// The build was cancelled with a [`CancellationToken`]
+    Cancelled,
+    /// The build took longer than its timeout of {0:?}
+    TimedOut(Duration),
     /// Cargo build failed, stderr: {0}
     Cargo(String),
 }
 
 impl BuildError {
     #[instrument]
-    fn from_stderr(mut stderr: ChildStderr) -> Self {
-        let mut stderr_buf = String::new();
-        if let Err(err) = stderr.read_to_string(&mut stderr_buf) {
-            return Self::RunCargo(err);
-        }
-
+    fn from_stderr_buf(stderr_buf: String) -> Self {
         lazy_static! {
             static ref NOT_FOUND_RE: Regex =
                 Regex::new(r"error: no \w+ target named `(?P<n>.*?)`").unwrap();
@@ -329,6 +808,18 @@ impl BuildError {
                 r"error: package ID specification `(?P<p>.*?)` did not match any packages"
             )
             .unwrap();
+            static ref LOCKFILE_RE: Regex = Regex::new(
+                r"error: (?:cannot (?:create|update) the lock file (?P<a>\S+) because|the lock file (?P<b>\S+) needs to be updated but) --(?:locked|frozen) was passed"
+            )
+            .unwrap();
+            static ref OFFLINE_RE: Regex = Regex::new(
+                r"attempting to make an HTTP request, but --(?:offline|frozen) was specified|you are in the offline mode|you're using offline mode"
+            )
+            .unwrap();
+            static ref UNSTABLE_RE: Regex = Regex::new(
+                r"error: (?P<m>the `-Z` flag is only accepted on the nightly channel.*|unknown `-Z` flag specified: .*|the `--[\w-]+` flag is unstable.*)"
+            )
+            .unwrap();
         }
 
         #[allow(clippy::option_if_let_else)]
@@ -338,6 +829,14 @@ impl BuildError {
         } else if let Some(caps) = PKG_NOT_FOUND_RE.captures(&stderr_buf) {
             let name = caps.name("p").unwrap().as_str().to_owned();
             BuildError::PackageNotFound(name)
+        } else if let Some(caps) = LOCKFILE_RE.captures(&stderr_buf) {
+            let path = caps.name("a").or_else(|| caps.name("b")).unwrap();
+            BuildError::LockfileOutdated(path.as_str().to_owned())
+        } else if OFFLINE_RE.is_match(&stderr_buf) {
+            BuildError::Offline(stderr_buf)
+        } else if let Some(caps) = UNSTABLE_RE.captures(&stderr_buf) {
+            let msg = caps.name("m").unwrap().as_str().to_owned();
+            BuildError::UnstableRejected(msg)
         } else {
             BuildError::Cargo(stderr_buf)
         }
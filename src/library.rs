@@ -0,0 +1,423 @@
+//!
+//! Main entrypoint: [`library::Compiler`]
+
+use std::process::Command;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use cargo_metadata::{ArtifactProfile, BuildScript, CompilerMessage, Message, PackageId, Target};
+use derivative::Derivative;
+use tracing::instrument;
+
+#[cfg(feature = "tokio")]
+use crate::run::run_cargo_async;
+use crate::{
+    event::{handle_output_event, handle_progress_event},
+    handle_compiler_msg,
+    metadata::Metadata,
+    options::{cargo_options_builders, CargoOptions},
+    progress::handle_progress,
+    run::{run_cargo, BuildState, CargoOutput},
+    BuildError, BuildEvent, BuildOutcome, Progress,
+};
+
+/// Compile a library
+///
+/// ```
+/// # use seacan::{library::{Compiler, CrateType}, PackageSpec};
+/// let artifacts = Compiler::lib()
+///     .workspace("samples/libs")
+///     .package(PackageSpec::name("libs"))
+///     .compile()?;
+/// let cdylib = artifacts
+///     .iter()
+///     .find(|artifact| artifact.crate_type == CrateType::Cdylib);
+/// # Ok::<_, seacan::BuildError>(())
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct Compiler {
+    #[derivative(Debug = "ignore")]
+    on_compiler_msg: Option<Box<dyn FnMut(CompilerMessage) + Send>>,
+    #[derivative(Debug = "ignore")]
+    on_progress: Option<Box<dyn FnMut(Progress) + Send>>,
+    #[derivative(Debug = "ignore")]
+    on_event: Option<Box<dyn FnMut(BuildEvent) + Send>>,
+    opts: CargoOptions,
+}
+
+impl Compiler {
+    /// Compile the library of each package (i.e. `--lib`).
+    ///
+    /// By default we build the library of every package in the workspace that
+    /// has one. Use [`Self::package`] to pick one.
+    #[must_use]
+    pub fn lib() -> Self {
+        Self {
+            on_compiler_msg: None,
+            on_progress: None,
+            on_event: None,
+            opts: CargoOptions::new(),
+        }
+    }
+
+    cargo_options_builders!();
+
+    /// Compile the described libraries
+    ///
+    /// We return one [`Artifact`] per crate type, so a library with
+    /// `crate-type = ["cdylib", "rlib"]` gives you two. Libraries of
+    /// dependencies outside the packages you selected are skipped.
+    ///
+    /// # Errors
+    ///
+    /// If cargo couldn't be run or the build failed. We try to give a more
+    /// specific [`BuildError`] than [`BuildError::Cargo`] where we can.
+    #[instrument(err)]
+    pub fn compile(&mut self) -> Result<Vec<Artifact>, BuildError> {
+        Ok(self.compile_outcome()?.artifacts)
+    }
+
+    /// Like [`Self::compile`], but also returns the rest of what cargo
+    /// reported: diagnostics, which units were fresh, and [`Self::timings`].
+    ///
+    /// # Errors
+    ///
+    /// See [`Self::compile`].
+    #[instrument(err)]
+    pub fn compile_outcome(&mut self) -> Result<BuildOutcome<Vec<Artifact>>, BuildError> {
+        let deadline = self.opts.deadline();
+        let packages = self.packages(&self.opts.metadata(&deadline)?)?;
+
+        let mut build = BuildState::new();
+        let (status, stderr) = run_cargo(self.command(), &deadline, |output| {
+            self.handle_output(output, &packages, &mut build);
+        })?;
+        build.finish(&self.opts, status, stderr)
+    }
+
+    /// Like [`Self::compile`], but doesn't block the thread.
+    ///
+    /// Cargo is killed if you drop the future before it finishes.
+    ///
+    /// # Errors
+    ///
+    /// See [`Self::compile`].
+    #[cfg(feature = "tokio")]
+    #[instrument(err)]
+    pub async fn compile_async(&mut self) -> Result<Vec<Artifact>, BuildError> {
+        Ok(self.compile_outcome_async().await?.artifacts)
+    }
+
+    /// Like [`Self::compile_outcome`], but doesn't block the thread.
+    ///
+    /// Cargo is killed if you drop the future before it finishes.
+    ///
+    /// # Errors
+    ///
+    /// See [`Self::compile`].
+    #[cfg(feature = "tokio")]
+    #[instrument(err)]
+    pub async fn compile_outcome_async(
+        &mut self,
+    ) -> Result<BuildOutcome<Vec<Artifact>>, BuildError> {
+        let deadline = self.opts.deadline();
+        // Not in one expression so that we don't hold `&self` across the
+        // await, which would need the callbacks to be `Sync`
+        let metadata = self.opts.metadata_async(&deadline).await?;
+        let packages = self.packages(&metadata)?;
+
+        let mut build = BuildState::new();
+        let (status, stderr) = run_cargo_async(self.command(), &deadline, |output| {
+            self.handle_output(output, &packages, &mut build);
+        })
+        .await?;
+        build.finish(&self.opts, status, stderr)
+    }
+
+    /// The packages whose libraries we return
+    fn packages(&self, metadata: &Metadata) -> Result<Vec<PackageId>, BuildError> {
+        Ok(metadata
+            .select(&self.opts.package)?
+            .into_iter()
+            .map(|pkg| pkg.id.clone())
+            .collect())
+    }
+
+    fn command(&self) -> Command {
+        let mut cmd = self.opts.command("build");
+        cmd.arg("--lib");
+        cmd
+    }
+
+    fn handle_output(
+        &mut self,
+        output: CargoOutput,
+        packages: &[PackageId],
+        build: &mut BuildState<Artifact>,
+    ) {
+        handle_output_event(&output, &mut self.on_event);
+        if let Some(progress) = build.progress.record(&output) {
+            handle_progress_event(&progress, &mut self.on_event);
+            handle_progress(progress, &mut self.on_progress);
+        }
+        if let CargoOutput::Message(msg) = output {
+            self.handle_msg(msg, packages, build);
+        }
+    }
+
+    fn handle_msg(
+        &mut self,
+        msg: Message,
+        packages: &[PackageId],
+        build: &mut BuildState<Artifact>,
+    ) {
+        build.record(&msg);
+        match msg {
+            Message::CompilerMessage(msg) => {
+                handle_compiler_msg(msg, &mut self.on_compiler_msg);
+            }
+            Message::CompilerArtifact(art) if packages.contains(&art.package_id) => {
+                let artifacts = Artifact::from_each_crate_type(
+                    &art,
+                    &self.opts.profile.name("dev"),
+                    self.opts.target_triple.as_ref(),
+                    build.build_scripts.find(&art),
+                );
+                build.artifacts.extend(artifacts);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The kind of library a compiler produced (i.e. the `crate-type` manifest
+/// key)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum CrateType {
+    /// A Rust library (`.rlib`). This is what `lib` means.
+    Rlib,
+    /// A Rust dynamic library (`.so`, `.dylib` or `.dll`)
+    Dylib,
+    /// A dynamic library with a C ABI (`.so`, `.dylib` or `.dll`)
+    Cdylib,
+    /// A static library with a C ABI (`.a` or `.lib`)
+    Staticlib,
+    /// A procedural macro (`.so`, `.dylib` or `.dll`)
+    ProcMacro,
+}
+
+impl CrateType {
+    /// Parse a crate type as cargo reports it.
+    ///
+    /// Returns `None` for crate types that aren't libraries (e.g. `bin`).
+    #[must_use]
+    pub fn from_cargo(crate_type: &str) -> Option<Self> {
+        match crate_type {
+            "lib" | "rlib" => Some(Self::Rlib),
+            "dylib" => Some(Self::Dylib),
+            "cdylib" => Some(Self::Cdylib),
+            "staticlib" => Some(Self::Staticlib),
+            "proc-macro" => Some(Self::ProcMacro),
+            _ => None,
+        }
+    }
+
+    /// If `path` looks like a file of this crate type
+    fn matches(self, path: &Utf8Path) -> bool {
+        let name = path.file_name().unwrap_or_default();
+        match path.extension().unwrap_or_default() {
+            "rlib" => self == Self::Rlib,
+            // On MSVC a `.lib` is either a static library or the import
+            // library that goes with a `.dll`
+            "a" => self == Self::Staticlib,
+            "lib" => self == Self::Staticlib && !name.ends_with(".dll.lib"),
+            "so" | "dylib" | "dll" => {
+                matches!(self, Self::Dylib | Self::Cdylib | Self::ProcMacro)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// A compiled library of one crate type
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub struct Artifact {
+    /// The package this artifact belongs to
+    pub package_id: PackageId,
+    /// The target this artifact was compiled for
+    pub target: Target,
+    /// The crate type of [`Self::path`]
+    pub crate_type: CrateType,
+    /// Path to the library file of this crate type (e.g. the `.so` of a
+    /// [`CrateType::Cdylib`] on Linux)
+    pub path: Utf8PathBuf,
+    /// The full paths to every file generated for the target
+    /// (e.g. the other crate types, metadata and separate debug info)
+    pub filenames: Vec<Utf8PathBuf>,
+    /// The profile this artifact was compiled with
+    pub profile: ArtifactProfile,
+    /// The name of the profile this artifact was compiled with (e.g. `dev`,
+    /// `release`, or a custom profile)
+    pub profile_name: String,
+    /// The target triple this artifact was compiled for (i.e. `--target`), or
+    /// `None` if it was compiled for the host
+    pub target_triple: Option<String>,
+    /// The enabled features for this artifact
+    pub features: Vec<String>,
+    /// The output of the package's build script, if it has one, from the run
+    /// this artifact was built with
+    pub build_script: Option<BuildScript>,
+    /// If true, then the files were already generated
+    pub fresh: bool,
+}
+
+impl Artifact {
+    fn from_each_crate_type(
+        art: &cargo_metadata::Artifact,
+        profile_name: &str,
+        target_triple: Option<&String>,
+        build_script: Option<&BuildScript>,
+    ) -> Vec<Self> {
+        art.target
+            .crate_types
+            .iter()
+            .filter_map(|crate_type| CrateType::from_cargo(crate_type))
+            .filter_map(|crate_type| {
+                let path = art.filenames.iter().find(|path| crate_type.matches(path))?;
+                Some(Self {
+                    package_id: art.package_id.clone(),
+                    target: art.target.clone(),
+                    crate_type,
+                    path: path.clone(),
+                    filenames: art.filenames.clone(),
+                    profile: art.profile.clone(),
+                    profile_name: profile_name.to_owned(),
+                    target_triple: target_triple.cloned(),
+                    features: art.features.clone(),
+                    build_script: build_script.cloned(),
+                    fresh: art.fresh,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_common::{init, Result},
+        PackageSpec,
+    };
+    use pretty_assertions::assert_eq;
+
+    fn crate_types(artifacts: &[Artifact]) -> Vec<CrateType> {
+        let mut types: Vec<_> = artifacts.iter().map(|a| a.crate_type).collect();
+        types.sort_by_key(|crate_type| format!("{crate_type:?}"));
+        types
+    }
+
+    #[test]
+    fn test_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<Compiler>();
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn test_compile_async_send() {
+        fn assert_send<T: Send>(_: &T) {}
+        assert_send(&Compiler::lib().compile_async());
+    }
+
+    #[test]
+    fn test_crate_types() -> Result {
+        init();
+        let artifacts = Compiler::lib()
+            .workspace("samples/libs")
+            .package(PackageSpec::name("libs"))
+            .compile()?;
+        assert_eq!(
+            vec![CrateType::Cdylib, CrateType::Rlib, CrateType::Staticlib],
+            crate_types(&artifacts)
+        );
+        for artifact in &artifacts {
+            assert!(artifact.path.is_file());
+            assert!(artifact.filenames.contains(&artifact.path));
+        }
+        let cdylib = artifacts
+            .iter()
+            .find(|a| a.crate_type == CrateType::Cdylib)
+            .unwrap();
+        assert_eq!(
+            Some(std::env::consts::DLL_EXTENSION),
+            cdylib.path.extension()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_proc_macro() -> Result {
+        init();
+        let artifacts = Compiler::lib()
+            .workspace("samples/libs")
+            .package(PackageSpec::name("proc_macro_lib"))
+            .compile()?;
+        assert_eq!(vec![CrateType::ProcMacro], crate_types(&artifacts));
+        Ok(())
+    }
+
+    #[test]
+    fn test_any_package() -> Result {
+        init();
+        let artifacts = Compiler::lib().workspace("samples/libs").compile()?;
+        assert_eq!(
+            vec![
+                CrateType::Cdylib,
+                CrateType::ProcMacro,
+                CrateType::Rlib,
+                CrateType::Staticlib
+            ],
+            crate_types(&artifacts)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_rlib_default() -> Result {
+        init();
+        let artifacts = Compiler::lib()
+            .workspace("samples/hello_world")
+            .package(PackageSpec::name("hello_world"))
+            .release(true)
+            .compile()?;
+        assert_eq!(1, artifacts.len());
+        assert_eq!(CrateType::Rlib, artifacts[0].crate_type);
+        assert_eq!("release", artifacts[0].profile_name);
+        Ok(())
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_compile_async() -> Result {
+        init();
+        let artifacts = Compiler::lib()
+            .workspace("samples/libs")
+            .package(PackageSpec::name("proc_macro_lib"))
+            .compile_async()
+            .await?;
+        assert_eq!(vec![CrateType::ProcMacro], crate_types(&artifacts));
+        Ok(())
+    }
+
+    #[test]
+    fn test_nonexistent_package() {
+        init();
+        let result = Compiler::lib()
+            .workspace("samples/libs")
+            .package(PackageSpec::name("nonexistent_package"))
+            .compile();
+        assert!(matches!(result, Err(BuildError::PackageNotFound(_))));
+    }
+}
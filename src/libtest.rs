@@ -0,0 +1,162 @@
+//! Libtest's unstable JSON output (`-Z unstable-options --format json`)
+//!
+//! See libtest's `formatters/json.rs`
+//! <https://github.com/rust-lang/rust/blob/master/library/test/src/formatters/json.rs>
+
+use std::{process::Command, time::Duration};
+
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::test::{TestFn, TestFnType};
+
+/// One line of libtest's JSON output. We only deserialize the fields we use.
+#[derive(Debug, Deserialize)]
+struct JsonLine {
+    /// `suite`, `test` or `bench`
+    #[serde(rename = "type")]
+    kind: String,
+    /// What happened, e.g. `discovered`, `started`, `ok` or `failed`
+    event: String,
+    name: Option<String>,
+    /// What libtest captured, on results
+    stdout: Option<String>,
+    /// In seconds, on results with `--report-time`
+    exec_time: Option<f64>,
+    /// If the test is ignored, when it's discovered
+    #[serde(default)]
+    ignore: bool,
+    /// Why the test is ignored, empty if there's no reason
+    ignore_message: Option<String>,
+}
+
+/// The result of a test that libtest reported as JSON
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct JsonResult {
+    /// `ok`, `failed`, `ignored`, or `timeout` with `--ensure-time`
+    pub(crate) event: String,
+    pub(crate) stdout: String,
+    pub(crate) exec_time: Option<Duration>,
+}
+
+/// Make a test binary print JSON. Test binaries from stable toolchains reject
+/// the unstable option unless `RUSTC_BOOTSTRAP` is set.
+pub(crate) fn json_args(cmd: &mut Command) -> &mut Command {
+    cmd.args(["-Z", "unstable-options", "--format", "json"])
+}
+
+/// Every line of `stdout` that's a libtest event. Tests can print straight to
+/// stdout, so we skip anything else.
+fn events(stdout: &str) -> impl Iterator<Item = JsonLine> + '_ {
+    stdout
+        .lines()
+        .filter(|line| line.starts_with('{'))
+        .filter_map(|line| serde_json::from_str(line).ok())
+}
+
+/// The tests `--list` discovered, or `None` if it didn't print JSON (older
+/// versions of libtest always list tests as terse text).
+pub(crate) fn parse_list(stdout: &str) -> Option<Vec<TestFn>> {
+    let mut saw_json = false;
+    let mut tests = Vec::new();
+    for event in events(stdout) {
+        saw_json = true;
+        let JsonLine {
+            kind,
+            event,
+            name,
+            ignore,
+            ignore_message,
+            ..
+        } = event;
+        let (Some(name), "discovered") = (name, event.as_str()) else {
+            continue;
+        };
+        let test_type = match kind.as_str() {
+            "test" => TestFnType::Test,
+            "bench" => TestFnType::Bench,
+            other => {
+                warn!(?name, "Ignoring unsupported test type `{}`", other);
+                continue;
+            }
+        };
+        tests.push(TestFn {
+            name,
+            test_type,
+            ignored: Some(ignore),
+            ignore_reason: ignore_message.filter(|message| !message.is_empty()),
+        });
+    }
+    saw_json.then_some(tests)
+}
+
+/// The result of the test `name`, if libtest reported one
+pub(crate) fn parse_result(name: &str, stdout: &str) -> Option<JsonResult> {
+    events(stdout)
+        .filter(|event| event.kind == "test" || event.kind == "bench")
+        .filter(|event| event.name.as_deref() == Some(name))
+        .find(|event| !matches!(event.event.as_str(), "started" | "discovered"))
+        .map(|event| JsonResult {
+            event: event.event,
+            stdout: event.stdout.unwrap_or_default(),
+            exec_time: event.exec_time.map(Duration::from_secs_f64),
+        })
+}
+
+/// If `stdout` has any libtest events. If not, the binary didn't understand
+/// the JSON options.
+pub(crate) fn is_json(stdout: &str) -> bool {
+    events(stdout).next().is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_parse_list() {
+        let stdout = r#"{ "type": "suite", "event": "discovery" }
+{ "type": "test", "event": "discovered", "name": "tests::a: b ... ok", "ignore": true, "ignore_message": "slow" }
+{ "type": "bench", "event": "discovered", "name": "bench", "ignore": false, "ignore_message": "" }
+{ "type": "suite", "event": "completed", "tests": 1, "benchmarks": 1, "total": 2, "ignored": 0 }
+"#;
+        assert_eq!(
+            Some(vec![
+                TestFn {
+                    name: "tests::a: b ... ok".into(),
+                    test_type: TestFnType::Test,
+                    ignored: Some(true),
+                    ignore_reason: Some("slow".into()),
+                },
+                TestFn {
+                    name: "bench".into(),
+                    test_type: TestFnType::Bench,
+                    ignored: Some(false),
+                    ignore_reason: None,
+                },
+            ]),
+            parse_list(stdout)
+        );
+        assert_eq!(None, parse_list("tests::a: test\n"));
+    }
+
+    #[test]
+    fn test_parse_result() {
+        let stdout = r#"{ "type": "suite", "event": "started", "test_count": 1 }
+{ "type": "test", "event": "started", "name": "a" }
+{ "not": "an event" }
+{ "type": "test", "name": "a", "event": "failed", "exec_time": 0.5, "stdout": "{ \"type\": \"test\" }\n" }
+"#;
+        assert_eq!(
+            Some(JsonResult {
+                event: "failed".into(),
+                stdout: "{ \"type\": \"test\" }\n".into(),
+                exec_time: Some(Duration::from_millis(500)),
+            }),
+            parse_result("a", stdout)
+        );
+        assert!(is_json(stdout));
+        assert_eq!(None, parse_result("b", stdout));
+    }
+}
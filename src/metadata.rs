@@ -0,0 +1,240 @@
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+use cargo_metadata::{Package, PackageId};
+use regex::Regex;
+use serde::Deserialize;
+use tracing::instrument;
+
+#[cfg(feature = "tokio")]
+use crate::run::run_output_async;
+use crate::{cancel::Deadline, options::CargoOptions, run::run_output, BuildError, PackageSpec};
+
+/// The parts of `cargo metadata --no-deps` we use
+#[derive(Debug, Clone)]
+pub(crate) struct Metadata {
+    pub(crate) inner: cargo_metadata::Metadata,
+    /// The `default-run` key of each package that has one. Our version of
+    /// `cargo_metadata` doesn't know about it.
+    default_runs: HashMap<PackageId, String>,
+    /// The manifest cargo would use if we didn't specify a package
+    current_manifest: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+struct RawMetadata {
+    packages: Vec<RawPackage>,
+}
+
+#[derive(Deserialize)]
+struct RawPackage {
+    id: PackageId,
+    default_run: Option<String>,
+}
+
+impl CargoOptions {
+    /// Run `cargo metadata` on the workspace we're building
+    #[instrument(err)]
+    pub(crate) fn metadata(&self, deadline: &Deadline) -> Result<Metadata, BuildError> {
+        let output = run_output::<BuildError>(self.metadata_command(), deadline)?;
+        self.parse_metadata(&output)
+    }
+
+    /// Like [`Self::metadata`], but doesn't block the thread
+    #[cfg(feature = "tokio")]
+    #[instrument(err)]
+    pub(crate) async fn metadata_async(&self, deadline: &Deadline) -> Result<Metadata, BuildError> {
+        let output = run_output_async::<BuildError>(self.metadata_command(), deadline).await?;
+        self.parse_metadata(&output)
+    }
+
+    fn metadata_command(&self) -> Command {
+        let mut cmd = self.base_command("metadata");
+        cmd.args(["--format-version", "1", "--no-deps"]);
+        cmd
+    }
+
+    fn parse_metadata(&self, output: &Output) -> Result<Metadata, BuildError> {
+        if !output.status.success() {
+            return Err(BuildError::from_stderr_buf(
+                String::from_utf8_lossy(&output.stderr).into_owned(),
+            ));
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let parse_err = |err: serde_json::Error| BuildError::Metadata(err.to_string());
+        let inner: cargo_metadata::Metadata = serde_json::from_str(&stdout).map_err(parse_err)?;
+        let raw: RawMetadata = serde_json::from_str(&stdout).map_err(parse_err)?;
+
+        let default_runs = raw
+            .packages
+            .into_iter()
+            .filter_map(|pkg| Some((pkg.id, pkg.default_run?)))
+            .collect();
+
+        Ok(Metadata {
+            inner,
+            default_runs,
+            current_manifest: self.current_manifest()?,
+        })
+    }
+
+    /// The manifest cargo starts from, either [`Self::manifest_path`] or the
+    /// nearest `Cargo.toml` to the directory we run in.
+    fn current_manifest(&self) -> Result<Option<PathBuf>, BuildError> {
+        let cwd = match &self.workspace {
+            Some(workspace) => workspace.clone(),
+            None => env::current_dir()?,
+        };
+
+        let manifest = match &self.manifest_path {
+            Some(manifest_path) => Some(cwd.join(manifest_path)),
+            None => cwd
+                .ancestors()
+                .map(|dir| dir.join("Cargo.toml"))
+                .find(|path| path.is_file()),
+        };
+
+        Ok(manifest.map(|path| canonicalize(&path)))
+    }
+}
+
+impl Metadata {
+    /// The `default-run` key of a package
+    pub(crate) fn default_run(&self, package: &PackageId) -> Option<&str> {
+        self.default_runs.get(package).map(String::as_str)
+    }
+
+    /// Every workspace member
+    pub(crate) fn members(&self) -> impl Iterator<Item = &Package> + Clone {
+        self.inner
+            .packages
+            .iter()
+            .filter(move |pkg| self.inner.workspace_members.contains(&pkg.id))
+    }
+
+    /// The workspace members `spec` selects, like `--package` does. So
+    /// [`PackageSpec::Any`] selects every member.
+    pub(crate) fn select(&self, spec: &PackageSpec) -> Result<Vec<&Package>, BuildError> {
+        let selected: Vec<_> = match spec {
+            PackageSpec::Any => self.members().collect(),
+            PackageSpec::Name(spec) => self
+                .members()
+                .filter(|pkg| matches_name(spec, pkg))
+                .collect(),
+            PackageSpec::Id(id) => self.members().filter(|pkg| &pkg.id == id).collect(),
+        };
+
+        if selected.is_empty() {
+            Err(BuildError::PackageNotFound(spec.as_repr().to_owned()))
+        } else {
+            Ok(selected)
+        }
+    }
+
+    /// The package cargo uses if you don't pass `--package` (e.g. for
+    /// `cargo run`), or every member if the workspace is virtual
+    pub(crate) fn current(&self) -> Vec<&Package> {
+        let current = self.current_manifest.as_ref().and_then(|manifest| {
+            self.members()
+                .find(|pkg| &canonicalize(pkg.manifest_path.as_std_path()) == manifest)
+        });
+        match current {
+            Some(pkg) => vec![pkg],
+            None => self.members().collect(),
+        }
+    }
+}
+
+/// If `pkg` matches a name spec: a name, a glob of names (e.g. `ws_*`), or a
+/// name and a (possibly partial) version (e.g. `name@1.2`, or the older
+/// `name:1.2`)
+fn matches_name(spec: &str, pkg: &Package) -> bool {
+    if spec.contains(['*', '?', '[']) {
+        return glob_regex(spec).is_some_and(|re| re.is_match(&pkg.name));
+    }
+
+    match spec.split_once(['@', ':']) {
+        Some((name, version)) => {
+            let actual = pkg.version.to_string();
+            pkg.name == name && (actual == version || actual.starts_with(&format!("{version}.")))
+        }
+        None => pkg.name == spec,
+    }
+}
+
+/// A regex matching what a Unix glob does (`*`, `?` and `[...]`), or `None`
+/// if the glob is malformed
+fn glob_regex(glob: &str) -> Option<Regex> {
+    let mut re = String::from("^");
+    let mut chars = glob.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => re.push_str(".*"),
+            '?' => re.push('.'),
+            '[' => {
+                re.push('[');
+                let mut class = chars.by_ref().take_while(|&c| c != ']').peekable();
+                if class.next_if_eq(&'!').is_some() {
+                    re.push('^');
+                }
+                for c in class {
+                    if c == '\\' || c == '^' || c == '[' {
+                        re.push('\\');
+                    }
+                    re.push(c);
+                }
+                re.push(']');
+            }
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push('$');
+    Regex::new(&re).ok()
+}
+
+/// Canonicalize if we can so that paths compare equal, otherwise leave as is.
+fn canonicalize(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_common::{init, Result};
+    use pretty_assertions::assert_eq;
+
+    fn select(spec: &str) -> std::result::Result<Vec<String>, BuildError> {
+        let mut opts = CargoOptions::new();
+        opts.workspace = Some("samples/hello_world".into());
+        let metadata = opts.metadata(&opts.deadline())?;
+        let mut names: Vec<_> = metadata
+            .select(&PackageSpec::name(spec))?
+            .into_iter()
+            .map(|pkg| pkg.name.clone())
+            .collect();
+        names.sort_unstable();
+        Ok(names)
+    }
+
+    #[test]
+    fn test_select() -> Result {
+        init();
+        assert_eq!(vec!["ws_member"], select("ws_member")?);
+        assert_eq!(vec!["hello_world"], select("hello_world@0.1.0")?);
+        assert_eq!(vec!["hello_world"], select("hello_world@0.1")?);
+        assert_eq!(vec!["hello_world"], select("hello_world:0.1.0")?);
+        assert_eq!(vec!["hello_world", "ws_member"], select("*")?);
+        assert_eq!(vec!["ws_member"], select("ws_*")?);
+        assert_eq!(vec!["hello_world"], select("[!w]*")?);
+        assert!(matches!(
+            select("hello_world@0.2"),
+            Err(BuildError::PackageNotFound(_))
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,421 @@
+use std::{
+    ffi::OsString,
+    path::PathBuf,
+    process::{Command, Stdio},
+    time::Duration,
+};
+
+use camino::Utf8PathBuf;
+
+use crate::{
+    cancel::Deadline, BuildError, BuildTimings, CancellationToken, CargoSpec, ConfigValue,
+    FeatureSpec, PackageSpec, ProfileSpec, UnstableFlag, MSG_FORMAT,
+};
+
+/// The builder methods every compiler shares, which set its [`CargoOptions`]
+/// (`opts`) and its `on_compiler_msg`, `on_progress` and `on_event`
+/// callbacks.
+macro_rules! cargo_options_builders {
+    () => {
+        /// Which cargo to run.
+        ///
+        /// By default [`CargoSpec::Default`](crate::CargoSpec::Default).
+        pub fn cargo(&mut self, cargo: $crate::CargoSpec) -> &mut Self {
+            self.opts.cargo = cargo;
+            self
+        }
+
+        /// The directory to run cargo in.
+        ///
+        /// By default the current working directory.
+        pub fn workspace(&mut self, path: impl Into<::std::path::PathBuf>) -> &mut Self {
+            self.opts.workspace = Some(path.into());
+            self
+        }
+
+        /// The `Cargo.toml` of the package or workspace to build
+        /// (i.e. `--manifest-path`).
+        ///
+        /// A relative path is relative to [`Self::workspace`]. By default cargo
+        /// searches the directory it runs in and its parents.
+        pub fn manifest_path(&mut self, path: impl Into<::std::path::PathBuf>) -> &mut Self {
+            self.opts.manifest_path = Some(path.into());
+            self
+        }
+
+        /// The package to build (i.e. `--package`).
+        ///
+        /// By default [`PackageSpec::Any`](crate::PackageSpec::Any).
+        pub fn package(&mut self, package: $crate::PackageSpec) -> &mut Self {
+            self.opts.package = package;
+            self
+        }
+
+        /// Callback for compiler messages.
+        ///
+        /// Regardless of if you specify this compiler messages will be logged at
+        /// debug level using [`tracing`].
+        pub fn on_compiler_msg(
+            &mut self,
+            cb: impl FnMut($crate::CompilerMessage) + Send + 'static,
+        ) -> &mut Self {
+            self.on_compiler_msg = Some(Box::new(cb));
+            self
+        }
+
+        /// Callback for each unit of the build starting, finishing, or being
+        /// found fresh, with how many units are done out of how many cargo plans.
+        ///
+        /// Regardless of if you specify this progress will be logged at debug
+        /// level using [`tracing`].
+        pub fn on_progress(
+            &mut self,
+            cb: impl FnMut($crate::Progress) + Send + 'static,
+        ) -> &mut Self {
+            self.on_progress = Some(Box::new(cb));
+            self.opts.progress = true;
+            self
+        }
+
+        /// Callback for everything that happens during the build, including
+        /// dependencies and what [`Self::on_compiler_msg`] and
+        /// [`Self::on_progress`] get. See [`BuildEvent`](crate::BuildEvent).
+        pub fn on_event(
+            &mut self,
+            cb: impl FnMut($crate::BuildEvent) + Send + 'static,
+        ) -> &mut Self {
+            self.on_event = Some(Box::new(cb));
+            self
+        }
+
+        /// Where to put the build artifacts.
+        ///
+        /// By default this is whatever cargo chooses by default.
+        pub fn target_dir(&mut self, target_dir: impl Into<$crate::Utf8PathBuf>) -> &mut Self {
+            self.opts.target_dir = Some(target_dir.into());
+            self
+        }
+
+        /// Enable or disable feature flags.
+        ///
+        /// By default this is whatever cargo chooses by default.
+        pub fn features(&mut self, features: $crate::FeatureSpec) -> &mut Self {
+            self.opts.features = Some(features);
+            self
+        }
+
+        /// If we should build in release mode.
+        ///
+        /// Shorthand for [`Self::profile`] with
+        /// [`ProfileSpec::Release`](crate::ProfileSpec::Release) or
+        /// [`ProfileSpec::Default`](crate::ProfileSpec::Default).
+        pub fn release(&mut self, is_release: bool) -> &mut Self {
+            self.opts.profile = if is_release {
+                $crate::ProfileSpec::Release
+            } else {
+                $crate::ProfileSpec::Default
+            };
+            self
+        }
+
+        /// The profile to build with.
+        ///
+        /// By default [`ProfileSpec::Default`](crate::ProfileSpec::Default).
+        pub fn profile(&mut self, profile: $crate::ProfileSpec) -> &mut Self {
+            self.opts.profile = profile;
+            self
+        }
+
+        /// The target triple to build for (i.e. `--target`).
+        ///
+        /// By default we build for the host. Note that cargo puts artifacts built
+        /// with an explicit target under `<target_dir>/<triple>/`.
+        pub fn target(&mut self, triple: impl Into<String>) -> &mut Self {
+            self.opts.target_triple = Some(triple.into());
+            self
+        }
+
+        /// If cargo must run without accessing the network (i.e. `--offline`).
+        pub fn offline(&mut self, offline: bool) -> &mut Self {
+            self.opts.offline = offline;
+            self
+        }
+
+        /// If cargo must not update `Cargo.lock` (i.e. `--locked`).
+        pub fn locked(&mut self, locked: bool) -> &mut Self {
+            self.opts.locked = locked;
+            self
+        }
+
+        /// Both [`Self::offline`] and [`Self::locked`] (i.e. `--frozen`).
+        pub fn frozen(&mut self, frozen: bool) -> &mut Self {
+            self.opts.frozen = frozen;
+            self
+        }
+
+        /// The number of parallel jobs to run (i.e. `--jobs`).
+        ///
+        /// Negative values are relative to the number of CPUs. By default this is
+        /// whatever cargo chooses by default.
+        pub fn jobs(&mut self, jobs: i32) -> &mut Self {
+            self.opts.jobs = Some(jobs);
+            self
+        }
+
+        /// If cargo should keep building as much as it can after a target fails
+        /// to build (i.e. `--keep-going`).
+        pub fn keep_going(&mut self, keep_going: bool) -> &mut Self {
+            self.opts.keep_going = keep_going;
+            self
+        }
+
+        /// Pass an unstable flag to cargo (i.e. `-Z <flag>`).
+        ///
+        /// Only nightly toolchains accept these, unless you enable
+        /// [`Self::rustc_bootstrap`]. If the toolchain rejects them we return
+        /// [`BuildError::UnstableRejected`](crate::BuildError::UnstableRejected).
+        pub fn unstable(&mut self, flag: $crate::UnstableFlag) -> &mut Self {
+            self.opts.unstable.push(flag);
+            self
+        }
+
+        /// Set `RUSTC_BOOTSTRAP=1` so that a stable toolchain accepts unstable
+        /// flags.
+        ///
+        /// This is explicitly unsupported by the Rust project, so only opt in if
+        /// you need to.
+        pub fn rustc_bootstrap(&mut self, rustc_bootstrap: bool) -> &mut Self {
+            self.opts.rustc_bootstrap = rustc_bootstrap;
+            self
+        }
+
+        /// Override a cargo configuration value for this build only
+        /// (i.e. `--config KEY=VALUE`).
+        ///
+        /// This doesn't touch any `.cargo/config.toml`. Use dotted keys, for
+        /// example `profile.dev.debug` or `build.rustflags`. Later overrides of the
+        /// same key take precedence.
+        ///
+        /// ```
+        /// # use seacan::bin::Compiler;
+        /// # let mut compiler = Compiler::bin("hello_world");
+        /// compiler
+        ///     .config("profile.dev.debug", 2)
+        ///     .config("build.rustflags", vec!["-C", "force-frame-pointers=yes"]);
+        /// ```
+        pub fn config(
+            &mut self,
+            key: impl Into<String>,
+            value: impl Into<$crate::ConfigValue>,
+        ) -> &mut Self {
+            self.opts.config.push((key.into(), value.into()));
+            self
+        }
+
+        /// Set an environment variable for the cargo process.
+        ///
+        /// For example `RUSTFLAGS`. By default cargo inherits our environment.
+        pub fn env(
+            &mut self,
+            key: impl Into<::std::ffi::OsString>,
+            value: impl Into<::std::ffi::OsString>,
+        ) -> &mut Self {
+            self.opts.env.push((key.into(), value.into()));
+            self
+        }
+
+        /// Ask cargo for a timing report (i.e. `--timings`). `compile_outcome`
+        /// returns it in [`BuildOutcome::timings`](crate::BuildOutcome::timings).
+        pub fn timings(&mut self, timings: bool) -> &mut Self {
+            self.opts.timings = timings;
+            self
+        }
+
+        /// Stop compiling when `token` is cancelled, killing cargo (or whatever
+        /// else we're running) and everything it started. The compile fails
+        /// with [`BuildError::Cancelled`](crate::BuildError::Cancelled).
+        pub fn cancellation_token(&mut self, token: $crate::CancellationToken) -> &mut Self {
+            self.opts.cancellation_token = Some(token);
+            self
+        }
+
+        /// Stop compiling if it takes longer than `timeout`, killing cargo (or
+        /// whatever else we're running) and everything it started. The compile
+        /// fails with [`BuildError::TimedOut`](crate::BuildError::TimedOut).
+        pub fn timeout(&mut self, timeout: ::std::time::Duration) -> &mut Self {
+            self.opts.timeout = Some(timeout);
+            self
+        }
+    };
+}
+pub(crate) use cargo_options_builders;
+
+/// The options shared by every compiler that determine how we invoke cargo
+#[derive(Debug, Clone)]
+#[allow(clippy::struct_excessive_bools)] // They mirror cargo's flags
+pub(crate) struct CargoOptions {
+    pub(crate) cargo: CargoSpec,
+    pub(crate) workspace: Option<PathBuf>,
+    pub(crate) manifest_path: Option<PathBuf>,
+    pub(crate) package: PackageSpec,
+    pub(crate) target_dir: Option<Utf8PathBuf>,
+    pub(crate) features: Option<FeatureSpec>,
+    pub(crate) profile: ProfileSpec,
+    pub(crate) target_triple: Option<String>,
+    pub(crate) config: Vec<(String, ConfigValue)>,
+    pub(crate) env: Vec<(OsString, OsString)>,
+    pub(crate) offline: bool,
+    pub(crate) locked: bool,
+    pub(crate) frozen: bool,
+    pub(crate) jobs: Option<i32>,
+    pub(crate) keep_going: bool,
+    pub(crate) unstable: Vec<UnstableFlag>,
+    pub(crate) rustc_bootstrap: bool,
+    pub(crate) timings: bool,
+    pub(crate) cancellation_token: Option<CancellationToken>,
+    pub(crate) timeout: Option<Duration>,
+    /// If cargo should draw its progress bar even though stderr isn't a
+    /// terminal, so that we can report progress
+    pub(crate) progress: bool,
+}
+
+impl CargoOptions {
+    pub(crate) fn new() -> Self {
+        Self {
+            cargo: CargoSpec::Default,
+            workspace: None,
+            manifest_path: None,
+            package: PackageSpec::Any,
+            target_dir: None,
+            features: None,
+            profile: ProfileSpec::Default,
+            target_triple: None,
+            config: Vec::new(),
+            env: Vec::new(),
+            offline: false,
+            locked: false,
+            frozen: false,
+            jobs: None,
+            keep_going: false,
+            unstable: Vec::new(),
+            rustc_bootstrap: false,
+            timings: false,
+            cancellation_token: None,
+            timeout: None,
+            progress: false,
+        }
+    }
+
+    /// When a compile starting now should stop
+    pub(crate) fn deadline(&self) -> Deadline {
+        Deadline::new(self.cancellation_token.clone(), self.timeout)
+    }
+
+    /// `cargo <subcommand>` with every option applied and stdio piped.
+    ///
+    /// The caller adds the arguments specific to what it's building.
+    pub(crate) fn command(&self, subcommand: &str) -> Command {
+        let mut cmd = self.base_command(subcommand);
+
+        cmd.arg(MSG_FORMAT)
+            .args(["--package", self.package.as_repr()]);
+
+        if let Some(features) = &self.features {
+            cmd.args(features.to_args());
+        }
+
+        cmd.args(self.profile.to_args());
+
+        if let Some(ref target_dir) = self.target_dir {
+            cmd.args(["--target-dir", target_dir.as_str()]);
+        }
+
+        if let Some(ref triple) = self.target_triple {
+            cmd.args(["--target", triple]);
+        }
+
+        if let Some(jobs) = self.jobs {
+            cmd.args(["--jobs", &jobs.to_string()]);
+        }
+
+        if self.keep_going {
+            // `cargo test` doesn't accept `--keep-going`. `--no-fail-fast` is the
+            // closest it has, but it doesn't reliably keep building after a
+            // compile error.
+            if subcommand == "test" {
+                cmd.arg("--no-fail-fast");
+            } else {
+                cmd.arg("--keep-going");
+            }
+        }
+
+        for flag in &self.unstable {
+            cmd.args(["-Z", &flag.to_arg()]);
+        }
+
+        if self.timings {
+            cmd.arg("--timings");
+        }
+
+        if self.progress {
+            // The width is required, but we only read the counts
+            cmd.env("CARGO_TERM_PROGRESS_WHEN", "always")
+                .env("CARGO_TERM_PROGRESS_WIDTH", "80");
+        }
+
+        cmd
+    }
+
+    /// The timings of a successful build, if we asked for them
+    pub(crate) fn read_timings(&self, stderr: &str) -> Result<Option<BuildTimings>, BuildError> {
+        if self.timings {
+            BuildTimings::from_stderr(stderr).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// `cargo <subcommand>` with only the options every subcommand accepts
+    /// (i.e. which workspace, how to access the network, and configuration).
+    pub(crate) fn base_command(&self, subcommand: &str) -> Command {
+        let mut cmd = self.cargo.command();
+
+        cmd.arg(subcommand)
+            .stderr(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stdin(Stdio::null());
+
+        if let Some(ref workspace) = self.workspace {
+            cmd.current_dir(workspace);
+        }
+
+        if let Some(ref manifest_path) = self.manifest_path {
+            cmd.arg("--manifest-path").arg(manifest_path);
+        }
+
+        if self.offline {
+            cmd.arg("--offline");
+        }
+
+        if self.locked {
+            cmd.arg("--locked");
+        }
+
+        if self.frozen {
+            cmd.arg("--frozen");
+        }
+
+        if self.rustc_bootstrap {
+            cmd.env("RUSTC_BOOTSTRAP", "1");
+        }
+
+        for (key, value) in &self.config {
+            cmd.arg("--config")
+                .arg(format!("{}={}", key, value.to_toml()));
+        }
+
+        cmd.envs(self.env.iter().map(|(k, v)| (k, v)));
+
+        cmd
+    }
+}
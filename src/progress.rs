@@ -0,0 +1,197 @@
+use cargo_metadata::{Message, PackageId, Target};
+use lazy_static::lazy_static;
+use regex::Regex;
+use tracing::debug;
+
+use crate::run::CargoOutput;
+
+/// A unit of the build (e.g. the lib of a package, one of its bins, or its
+/// build script) started, finished, or was found fresh
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Progress {
+    /// What happened to the unit
+    pub kind: ProgressKind,
+    /// The name of the unit's package
+    pub package: String,
+    /// The unit's package. `None` for [`ProgressKind::Started`], because cargo
+    /// only tells us the name and version.
+    pub package_id: Option<PackageId>,
+    /// The unit's target. `None` for [`ProgressKind::Started`] and
+    /// [`ProgressKind::BuildScriptRan`], because cargo doesn't say.
+    pub target: Option<Target>,
+    /// How many units have finished, been found fresh, or run, including
+    /// this one
+    pub completed: usize,
+    /// How many units cargo plans to finish, from cargo's progress bar.
+    /// `None` until cargo first draws it.
+    pub total: Option<usize>,
+}
+
+/// See [`Progress::kind`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ProgressKind {
+    /// Cargo started compiling the package (i.e. its `Compiling` status line)
+    Started,
+    /// The unit finished compiling
+    Finished,
+    /// The unit was already up to date, so cargo didn't compile it
+    Fresh,
+    /// The package's build script ran, or cargo reused its previous output
+    BuildScriptRan,
+}
+
+lazy_static! {
+    static ref COMPILING_RE: Regex = Regex::new(r"^\s*Compiling (?P<n>\S+) v").unwrap();
+    static ref PROGRESS_BAR_RE: Regex =
+        Regex::new(r"^\s*Building \[[ =>]*\] (?P<c>\d+)/(?P<t>\d+)").unwrap();
+}
+
+/// Counts the units cargo has done so far
+#[derive(Debug, Default)]
+pub(crate) struct ProgressTracker {
+    completed: usize,
+    total: Option<usize>,
+}
+
+impl ProgressTracker {
+    /// The progress `output` represents, if any
+    pub(crate) fn record(&mut self, output: &CargoOutput) -> Option<Progress> {
+        let (kind, package, package_id, target) = match output {
+            CargoOutput::Message(Message::CompilerArtifact(art)) => {
+                let kind = if art.fresh {
+                    ProgressKind::Fresh
+                } else {
+                    ProgressKind::Finished
+                };
+                (kind, None, Some(&art.package_id), Some(&art.target))
+            }
+            CargoOutput::Message(Message::BuildScriptExecuted(script)) => (
+                ProgressKind::BuildScriptRan,
+                None,
+                Some(&script.package_id),
+                None,
+            ),
+            CargoOutput::Stderr(line) => {
+                if let Some(caps) = PROGRESS_BAR_RE.captures(line) {
+                    let total = caps.name("t").unwrap().as_str().parse().ok();
+                    self.total = self.total.max(total);
+                    return None;
+                }
+                let caps = COMPILING_RE.captures(line)?;
+                let name = caps.name("n").unwrap().as_str().to_owned();
+                (ProgressKind::Started, Some(name), None, None)
+            }
+            CargoOutput::Message(_) => return None,
+        };
+
+        if kind != ProgressKind::Started {
+            self.completed += 1;
+        }
+
+        let package = package
+            .or_else(|| package_id.map(package_name))
+            .unwrap_or_default();
+        Some(Progress {
+            kind,
+            package,
+            package_id: package_id.cloned(),
+            target: target.cloned(),
+            completed: self.completed,
+            // Cargo's count can lag behind ours
+            total: self.total.map(|total| total.max(self.completed)),
+        })
+    }
+}
+
+pub(crate) fn handle_progress(
+    progress: Progress,
+    cb: &mut Option<Box<dyn FnMut(Progress) + Send>>,
+) {
+    debug!(?progress, "Build progress");
+    if let Some(cb) = cb {
+        cb(progress);
+    }
+}
+
+/// If a line of stderr is cargo redrawing its progress bar
+pub(crate) fn is_progress_bar(line: &str) -> bool {
+    PROGRESS_BAR_RE.is_match(line)
+}
+
+/// The name of a package from its ID, which is either
+/// `name version (source)`, `source#name@version`, or `source#version` if
+/// the name is the last part of the source's path.
+fn package_name(id: &PackageId) -> String {
+    let repr = id.repr.as_str();
+    let name = match repr.split_once('#') {
+        None => repr.split(' ').next().unwrap_or(repr),
+        Some((_, fragment)) if fragment.contains('@') => {
+            fragment.split('@').next().unwrap_or(fragment)
+        }
+        Some((source, _)) => source
+            .split('?')
+            .next()
+            .unwrap_or(source)
+            .trim_end_matches('/')
+            .rsplit('/')
+            .next()
+            .unwrap_or(source),
+    };
+    name.to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn id(repr: &str) -> PackageId {
+        PackageId {
+            repr: repr.to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_package_name() {
+        assert_eq!(
+            "serde",
+            package_name(&id(
+                "serde 1.0.126 (registry+https://github.com/rust-lang/crates.io-index)"
+            ))
+        );
+        assert_eq!(
+            "serde",
+            package_name(&id(
+                "registry+https://github.com/rust-lang/crates.io-index#serde@1.0.126"
+            ))
+        );
+        assert_eq!(
+            "hello_world",
+            package_name(&id("path+file:///root/samples/hello_world#0.1.0"))
+        );
+        assert_eq!(
+            "member",
+            package_name(&id("path+file:///root/samples/ws#member@0.1.0"))
+        );
+    }
+
+    #[test]
+    fn test_progress_bar() {
+        let mut tracker = ProgressTracker::default();
+        let line = "    Building [========>                    ] 1/3: build_script(build)";
+        assert!(is_progress_bar(line));
+        assert_eq!(None, tracker.record(&CargoOutput::Stderr(line.to_owned())));
+
+        let started = tracker
+            .record(&CargoOutput::Stderr(
+                "   Compiling serde v1.0.126".to_owned(),
+            ))
+            .unwrap();
+        assert_eq!(ProgressKind::Started, started.kind);
+        assert_eq!("serde", started.package);
+        assert_eq!(0, started.completed);
+        assert_eq!(Some(3), started.total);
+    }
+}
@@ -0,0 +1,416 @@
+//! Running cargo and collecting what it tells us, shared by every compiler
+//! and by the blocking and async APIs
+
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader, Read},
+    panic,
+    process::{Command, ExitStatus, Output},
+    sync::mpsc,
+    thread,
+};
+
+use cargo_metadata::{Artifact, BuildScript, Message, PackageId};
+
+use crate::{
+    cancel::Deadline,
+    options::CargoOptions,
+    progress::{is_progress_bar, ProgressTracker},
+    BuildError, BuildOutcome, BuildSummary,
+};
+
+/// What we've collected from cargo's messages so far
+#[derive(Debug)]
+pub(crate) struct BuildState<T> {
+    pub(crate) artifacts: Vec<T>,
+    pub(crate) build_scripts: BuildScripts,
+    pub(crate) summary: BuildSummary,
+    pub(crate) progress: ProgressTracker,
+}
+
+impl<T> BuildState<T> {
+    pub(crate) fn new() -> Self {
+        Self {
+            artifacts: Vec::new(),
+            build_scripts: BuildScripts::default(),
+            summary: BuildSummary::default(),
+            progress: ProgressTracker::default(),
+        }
+    }
+
+    /// Record what every compiler needs to know about a message. The caller
+    /// handles anything specific to what it's building.
+    pub(crate) fn record(&mut self, msg: &Message) {
+        self.summary.record(msg);
+        if let Message::BuildScriptExecuted(script) = msg {
+            self.build_scripts.record(script.clone());
+        }
+    }
+
+    pub(crate) fn finish(
+        self,
+        opts: &CargoOptions,
+        status: ExitStatus,
+        stderr: String,
+    ) -> Result<BuildOutcome<Vec<T>>, BuildError> {
+        if status.success() {
+            let timings = opts.read_timings(&stderr)?;
+            Ok(self.summary.into_outcome(self.artifacts, timings))
+        } else {
+            Err(self.summary.error(stderr))
+        }
+    }
+}
+
+/// Every time a build script ran. A package's build script can run more than
+/// once, e.g. for the host and for `--target` if it's both a build dependency
+/// and a normal one.
+#[derive(Debug, Default)]
+pub(crate) struct BuildScripts(HashMap<PackageId, Vec<BuildScript>>);
+
+impl BuildScripts {
+    fn record(&mut self, script: BuildScript) {
+        self.0
+            .entry(script.package_id.clone())
+            .or_default()
+            .push(script);
+    }
+
+    /// The run of the build script that fed `artifact`, which is the one that
+    /// put its output in the same target directory (e.g. `target/debug` or
+    /// `target/<triple>/debug`).
+    pub(crate) fn find(&self, artifact: &Artifact) -> Option<&BuildScript> {
+        let runs = self.0.get(&artifact.package_id)?;
+        let same_dir = runs.iter().rev().find(|script| {
+            // `OUT_DIR` is `<dir>/build/<package>-<hash>/out`
+            script.out_dir.ancestors().nth(3).is_some_and(|dir| {
+                artifact
+                    .filenames
+                    .iter()
+                    .any(|filename| filename.starts_with(dir))
+            })
+        });
+        match runs.as_slice() {
+            [only] => same_dir.or(Some(only)),
+            _ => same_dir,
+        }
+    }
+}
+
+/// Something cargo printed
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)] // Most output is messages anyway
+pub(crate) enum CargoOutput {
+    /// A message on stdout
+    Message(Message),
+    /// A line of stderr. Each redraw of the progress bar is its own line.
+    Stderr(String),
+}
+
+/// Run cargo to completion, handing everything it prints to `on_output` as
+/// it prints it.
+///
+/// Returns how cargo exited and everything it wrote to stderr except the
+/// progress bar. Cargo is killed if `deadline` passes first.
+pub(crate) fn run_cargo(
+    mut cmd: Command,
+    deadline: &Deadline,
+    mut on_output: impl FnMut(CargoOutput),
+) -> Result<(ExitStatus, String), BuildError> {
+    deadline.prepare(&mut cmd)?;
+    let mut child = cmd.spawn()?;
+    let watchdog = deadline.watch(&child);
+
+    let result = (|| {
+        // We always pipe stdout and stderr
+        let stdout = child.stdout.take().unwrap();
+        let stderr = child.stderr.take().unwrap();
+
+        // Read both at once so that neither pipe fills up and blocks cargo,
+        // but call `on_output` from this thread
+        let (tx, rx) = mpsc::channel();
+        let stdout_reader = thread::spawn({
+            let tx = tx.clone();
+            move || {
+                for msg in Message::parse_stream(BufReader::new(stdout)) {
+                    // The receiver only hangs up once we're done
+                    let _ = tx.send(CargoOutput::Message(msg?));
+                }
+                Ok::<_, io::Error>(())
+            }
+        });
+        let stderr_reader = thread::spawn(move || {
+            for line in BufReader::new(stderr).split(b'\n') {
+                for line in split_redraws(&String::from_utf8_lossy(&line?)) {
+                    let _ = tx.send(CargoOutput::Stderr(line.to_owned()));
+                }
+            }
+            Ok::<_, io::Error>(())
+        });
+
+        let mut stderr_buf = String::new();
+        for output in rx {
+            if let CargoOutput::Stderr(line) = &output {
+                keep_stderr(&mut stderr_buf, line);
+            }
+            on_output(output);
+        }
+
+        for reader in [stdout_reader, stderr_reader] {
+            reader
+                .join()
+                .unwrap_or_else(|err| panic::resume_unwind(err))?;
+        }
+        Ok::<_, io::Error>((watchdog.wait(&mut child)?, stderr_buf))
+    })();
+
+    // If we killed cargo that's the error, not whatever happened as it died
+    watchdog.finish()?;
+    Ok(result?)
+}
+
+/// Run a command to completion, collecting its output like
+/// [`Command::output`]. It's killed if `deadline` passes first.
+pub(crate) fn run_output<E>(mut cmd: Command, deadline: &Deadline) -> Result<Output, E>
+where
+    E: From<BuildError> + From<io::Error>,
+{
+    deadline.prepare(&mut cmd)?;
+    let mut child = cmd.spawn()?;
+    let watchdog = deadline.watch(&child);
+
+    // Like `Child::wait_with_output`, but waiting with the watchdog
+    let output = (|| {
+        let mut stdout = child.stdout.take();
+        let mut stderr = child.stderr.take();
+        let stderr_reader = thread::spawn(move || {
+            let mut buf = Vec::new();
+            if let Some(stderr) = &mut stderr {
+                stderr.read_to_end(&mut buf)?;
+            }
+            Ok::<_, io::Error>(buf)
+        });
+        let mut stdout_buf = Vec::new();
+        if let Some(stdout) = &mut stdout {
+            stdout.read_to_end(&mut stdout_buf)?;
+        }
+        let stderr_buf = stderr_reader
+            .join()
+            .unwrap_or_else(|err| panic::resume_unwind(err))?;
+        Ok::<_, io::Error>(Output {
+            status: watchdog.wait(&mut child)?,
+            stdout: stdout_buf,
+            stderr: stderr_buf,
+        })
+    })();
+
+    watchdog.finish()?;
+    Ok(output?)
+}
+
+/// Like [`run_cargo`], but doesn't block the thread.
+///
+/// Cargo is killed if the future is dropped before it exits, but not anything
+/// it started unless `deadline` has a way to stop it.
+#[cfg(feature = "tokio")]
+pub(crate) async fn run_cargo_async(
+    mut cmd: Command,
+    deadline: &Deadline,
+    mut on_output: impl FnMut(CargoOutput),
+) -> Result<(ExitStatus, String), BuildError> {
+    use tokio::io::AsyncBufReadExt;
+
+    deadline.prepare(&mut cmd)?;
+    let mut child = tokio::process::Command::from(cmd)
+        .kill_on_drop(true)
+        .spawn()?;
+    let pid = child.id();
+
+    let result = async {
+        // We always pipe stdout and stderr
+        let mut stdout = tokio::io::BufReader::new(child.stdout.take().unwrap()).lines();
+        let mut stderr = tokio::io::BufReader::new(child.stderr.take().unwrap()).split(b'\n');
+
+        let mut stderr_buf = String::new();
+        let (mut stdout_done, mut stderr_done) = (false, false);
+        while !(stdout_done && stderr_done) {
+            tokio::select! {
+                line = stdout.next_line(), if !stdout_done => match line? {
+                    // The same as `Message::parse_stream`
+                    Some(line) => on_output(CargoOutput::Message(
+                        serde_json::from_str(&line).unwrap_or(Message::TextLine(line)),
+                    )),
+                    None => stdout_done = true,
+                },
+                line = stderr.next_segment(), if !stderr_done => match line? {
+                    Some(line) => {
+                        for line in split_redraws(&String::from_utf8_lossy(&line)) {
+                            keep_stderr(&mut stderr_buf, line);
+                            on_output(CargoOutput::Stderr(line.to_owned()));
+                        }
+                    }
+                    None => stderr_done = true,
+                },
+            }
+        }
+
+        Ok::<_, io::Error>((child.wait().await?, stderr_buf))
+    };
+
+    // Once cargo has exited we don't kill it, so prefer that
+    tokio::select! {
+        biased;
+        result = result => Ok(result?),
+        // If we killed cargo that's the error, not whatever happened as it died
+        err = deadline.kill_when_stopped(pid) => Err(err),
+    }
+}
+
+/// Like [`run_output`], but doesn't block the thread.
+///
+/// The command is killed if the future is dropped before it exits.
+#[cfg(feature = "tokio")]
+pub(crate) async fn run_output_async<E>(mut cmd: Command, deadline: &Deadline) -> Result<Output, E>
+where
+    E: From<BuildError> + From<io::Error>,
+{
+    deadline.prepare(&mut cmd)?;
+    let child = tokio::process::Command::from(cmd)
+        .kill_on_drop(true)
+        .spawn()?;
+    let pid = child.id();
+    tokio::select! {
+        biased;
+        output = child.wait_with_output() => Ok(output?),
+        err = deadline.kill_when_stopped(pid) => Err(err.into()),
+    }
+}
+
+/// Split a line of stderr where cargo returns to the start of the line to
+/// redraw its progress bar
+fn split_redraws(line: &str) -> impl Iterator<Item = &str> {
+    let redraws = line.contains('\r');
+    line.split('\r')
+        .map(move |line| if redraws { line.trim_end() } else { line })
+        .filter(move |line| !(redraws && line.is_empty()))
+}
+
+/// Keep a line of stderr for [`BuildError`]s, unless it's the progress bar
+fn keep_stderr(stderr_buf: &mut String, line: &str) {
+    if !is_progress_bar(line) {
+        stderr_buf.push_str(line);
+        stderr_buf.push('\n');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_common::{init, Result};
+    use pretty_assertions::assert_eq;
+    use std::process::Stdio;
+
+    fn build_script(out_dir: &str) -> BuildScript {
+        serde_json::from_value(serde_json::json!({
+            "package_id": "pkg 0.1.0 (path+file:///pkg)",
+            "linked_libs": [],
+            "linked_paths": [],
+            "cfgs": [],
+            "env": [],
+            "out_dir": out_dir,
+        }))
+        .unwrap()
+    }
+
+    fn artifact(filename: &str) -> Artifact {
+        serde_json::from_value(serde_json::json!({
+            "package_id": "pkg 0.1.0 (path+file:///pkg)",
+            "target": {
+                "name": "pkg",
+                "kind": ["bin"],
+                "crate_types": ["bin"],
+                "src_path": "/pkg/src/main.rs",
+            },
+            "profile": {
+                "opt_level": "0",
+                "debuginfo": 2,
+                "debug_assertions": true,
+                "overflow_checks": true,
+                "test": false,
+            },
+            "features": [],
+            "filenames": [filename],
+            "executable": filename,
+            "fresh": false,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_build_scripts() {
+        let host = "/pkg/target/debug/build/pkg-1/out";
+        let target = "/pkg/target/wasm32-wasi/debug/build/pkg-2/out";
+        let mut scripts = BuildScripts::default();
+        scripts.record(build_script(target));
+        scripts.record(build_script(host));
+
+        let find = |filename| {
+            scripts
+                .find(&artifact(filename))
+                .map(|script| script.out_dir.to_string())
+        };
+        assert_eq!(
+            Some(target.to_owned()),
+            find("/pkg/target/wasm32-wasi/debug/pkg")
+        );
+        assert_eq!(Some(host.to_owned()), find("/pkg/target/debug/deps/pkg-3"));
+        assert_eq!(None, find("/elsewhere/pkg"));
+    }
+
+    #[test]
+    fn test_split_redraws() {
+        let line =
+            "    Building [=>  ] 1/3: a   \r    Building [==> ] 2/3: b   \r   \r    Finished";
+        assert_eq!(
+            vec![
+                "    Building [=>  ] 1/3: a",
+                "    Building [==> ] 2/3: b",
+                "    Finished"
+            ],
+            split_redraws(line).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec!["  kept  "],
+            split_redraws("  kept  ").collect::<Vec<_>>()
+        );
+    }
+
+    /// More stderr than fits in a pipe before the last message on stdout
+    #[cfg(unix)]
+    #[test]
+    fn test_lots_of_stderr() -> Result {
+        init();
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c")
+            .arg(
+                r#"head -c 1000000 /dev/zero | tr '\0' a | fold -w 100 >&2
+                echo '{"reason":"build-finished","success":true}'"#,
+            )
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        let mut lines = 0;
+        let mut finished = None;
+        let (status, stderr) = run_cargo(cmd, &Deadline::new(None, None), |output| match output {
+            CargoOutput::Stderr(_) => lines += 1,
+            CargoOutput::Message(Message::BuildFinished(msg)) => finished = Some(msg),
+            CargoOutput::Message(_) => {}
+        })?;
+
+        assert!(status.success());
+        assert_eq!(Some(true), finished.map(|msg| msg.success));
+        assert_eq!(10_000, lines);
+        assert_eq!(1_010_000, stderr.len());
+        Ok(())
+    }
+}
@@ -0,0 +1,836 @@
+//! Running the tests [`test::Compiler`](crate::test::Compiler) found
+
+use std::{
+    ffi::OsString,
+    io,
+    path::PathBuf,
+    process::{Command, Output, Stdio},
+    time::{Duration, Instant},
+};
+
+use camino::Utf8PathBuf;
+use cargo_metadata::{Package, PackageId, Target};
+use derivative::Derivative;
+use tracing::{debug, instrument};
+
+#[cfg(feature = "tokio")]
+use crate::run::run_output_async;
+use crate::{
+    cancel::Deadline,
+    libtest,
+    metadata::Metadata,
+    run::run_output,
+    test::{Artifact, TestFn},
+    BuildError, CancellationToken,
+};
+
+/// Run the tests in compiled [`Artifact`]s
+///
+/// Each test runs on its own in a new process, so that we can time it, kill it
+/// if it takes too long, and tell its output apart from the rest. Like
+/// `cargo test`, tests run in the directory of their package, with the
+/// environment variables cargo sets (e.g. `CARGO_MANIFEST_DIR`, `CARGO_PKG_*`,
+/// and `OUT_DIR` and `cargo:rustc-env` from a build script).
+///
+/// ```
+/// # use seacan::test::{Compiler, NameSpec, Runner, TypeSpec};
+/// let artifacts = Compiler::new(NameSpec::substring("test_in_lib_1"), TypeSpec::Lib)
+///     .workspace("samples/hello_world")
+///     .compile()?;
+/// let run = Runner::new().run(&artifacts)?;
+/// assert!(run.summary.success());
+/// # Ok::<_, Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Derivative)]
+#[derivative(Debug, Default)]
+pub struct Runner {
+    current_dir: Option<PathBuf>,
+    env: Vec<(OsString, OsString)>,
+    test_timeout: Option<Duration>,
+    timeout: Option<Duration>,
+    cancellation_token: Option<CancellationToken>,
+    libtest_json: bool,
+    rustc_bootstrap: bool,
+    #[derivative(Debug = "ignore")]
+    on_result: Option<Box<dyn FnMut(TestResult) + Send>>,
+}
+
+/// The results of [`Runner::run`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct TestRun {
+    /// Every test we ran, in the order we ran them
+    pub results: Vec<TestResult>,
+    /// Totals across [`Self::results`]
+    pub summary: TestSummary,
+}
+
+/// How many tests had each [`TestOutcome`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub struct TestSummary {
+    /// How many tests passed
+    pub passed: usize,
+    /// How many tests failed
+    pub failed: usize,
+    /// How many tests were ignored
+    pub ignored: usize,
+    /// How many tests timed out
+    pub timed_out: usize,
+    /// How long running every test took
+    pub duration: Duration,
+}
+
+impl TestSummary {
+    /// If no test failed or timed out
+    #[must_use]
+    pub fn success(&self) -> bool {
+        self.failed == 0 && self.timed_out == 0
+    }
+
+    fn record(&mut self, result: &TestResult) {
+        let count = match result.outcome {
+            TestOutcome::Passed => &mut self.passed,
+            TestOutcome::Failed => &mut self.failed,
+            TestOutcome::Ignored => &mut self.ignored,
+            TestOutcome::TimedOut => &mut self.timed_out,
+        };
+        *count += 1;
+        self.duration += result.duration;
+    }
+}
+
+/// The result of running one test
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct TestResult {
+    /// The package the test is in
+    pub package_id: PackageId,
+    /// The target the test is in
+    pub target: Target,
+    /// The test
+    pub test: TestFn,
+    /// How it went
+    pub outcome: TestOutcome,
+    /// What libtest captured while the test ran: everything it printed with
+    /// `print!`, `eprint!` and the like, including its panic message. Libtest
+    /// doesn't keep what it printed to stdout and stderr apart. Empty if we
+    /// killed it for timing out.
+    pub stdout: String,
+    /// What the test binary wrote to stderr that libtest didn't capture (e.g.
+    /// from a process the test started)
+    pub stderr: String,
+    /// How long the test binary took to run the test, including starting up.
+    /// With [`Runner::libtest_json`] it's how long libtest says the test
+    /// itself took.
+    pub duration: Duration,
+}
+
+/// See [`TestResult::outcome`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum TestOutcome {
+    /// The test passed (or panicked, if it's `#[should_panic]`)
+    Passed,
+    /// The test failed, or the test binary crashed running it
+    Failed,
+    /// The test is `#[ignore]`d, and you didn't ask for ignored tests with
+    /// [`Compiler::ignored`](crate::test::Compiler::ignored)
+    Ignored,
+    /// The test took longer than [`Runner::test_timeout`], so we killed it, or
+    /// libtest reported that it took longer than `--ensure-time` allows
+    TimedOut,
+}
+
+impl Runner {
+    /// Run tests with the default settings
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Run tests in `path` instead of the directory of their package
+    pub fn current_dir(&mut self, path: impl Into<PathBuf>) -> &mut Self {
+        self.current_dir = Some(path.into());
+        self
+    }
+
+    /// Set an environment variable for the test binaries. Can be called
+    /// multiple times. These override the variables cargo would set.
+    pub fn env(&mut self, key: impl Into<OsString>, value: impl Into<OsString>) -> &mut Self {
+        self.env.push((key.into(), value.into()));
+        self
+    }
+
+    /// Kill a test and report it as [`TestOutcome::TimedOut`] if it takes
+    /// longer than `timeout`, then carry on with the rest.
+    pub fn test_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.test_timeout = Some(timeout);
+        self
+    }
+
+    /// Stop running tests if the whole run takes longer than `timeout`. The
+    /// test running at the time is killed, and [`Self::run`] returns
+    /// [`BuildError::TimedOut`].
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Stop running tests once `token` is cancelled. The test running at the
+    /// time is killed, and [`Self::run`] returns [`BuildError::Cancelled`].
+    pub fn cancellation_token(&mut self, token: CancellationToken) -> &mut Self {
+        self.cancellation_token = Some(token);
+        self
+    }
+
+    /// Run tests with libtest's unstable JSON output (i.e.
+    /// `-Z unstable-options --format json`) instead of parsing its text. It
+    /// also means [`TestResult::duration`] is how long libtest says the test
+    /// took.
+    ///
+    /// Only takes effect with [`Self::rustc_bootstrap`], without which test
+    /// binaries from stable toolchains reject it. If a test binary doesn't
+    /// support it we fall back to text.
+    pub fn libtest_json(&mut self, libtest_json: bool) -> &mut Self {
+        self.libtest_json = libtest_json;
+        self
+    }
+
+    /// Set `RUSTC_BOOTSTRAP=1` for the test binaries when running them with
+    /// [`Self::libtest_json`], so that ones from stable toolchains accept it.
+    /// The tests see it too.
+    ///
+    /// This is explicitly unsupported by the Rust project, so only opt in if
+    /// you need to.
+    pub fn rustc_bootstrap(&mut self, rustc_bootstrap: bool) -> &mut Self {
+        self.rustc_bootstrap = rustc_bootstrap;
+        self
+    }
+
+    /// Called with the result of each test as soon as it finishes
+    pub fn on_result(&mut self, cb: impl FnMut(TestResult) + Send + 'static) -> &mut Self {
+        self.on_result = Some(Box::new(cb));
+        self
+    }
+
+    /// Run every test in [`Artifact::tests`] of each artifact, one at a time.
+    ///
+    /// # Errors
+    ///
+    /// If we couldn't run a test binary, couldn't understand what it printed,
+    /// or the run was cancelled or timed out.
+    #[instrument(skip(artifacts), err)]
+    pub fn run(&mut self, artifacts: &[Artifact]) -> Result<TestRun, RunError> {
+        let deadline = Deadline::new(self.cancellation_token.clone(), self.timeout);
+        let mut run = TestRun::default();
+
+        for artifact in artifacts {
+            let mut json = self.libtest_json && self.rustc_bootstrap;
+            for test in &artifact.tests {
+                let result = loop {
+                    let start = Instant::now();
+                    let output = run_output::<RunError>(
+                        self.command(artifact, test, json),
+                        &deadline.limit(self.test_timeout),
+                    );
+                    let elapsed = start.elapsed();
+                    if let Some(result) =
+                        test_result(artifact, test, output, elapsed, &deadline, json)?
+                    {
+                        break result;
+                    }
+                    debug!(test = %test.name, "Running as JSON failed, falling back to text");
+                    json = false;
+                };
+                self.record(&mut run, result);
+            }
+        }
+
+        Ok(run)
+    }
+
+    /// Like [`Self::run`], but doesn't block the thread.
+    ///
+    /// The test running at the time is killed if you drop the future before
+    /// it finishes.
+    ///
+    /// # Errors
+    ///
+    /// See [`Self::run`].
+    #[cfg(feature = "tokio")]
+    #[instrument(skip(artifacts), err)]
+    pub async fn run_async(&mut self, artifacts: &[Artifact]) -> Result<TestRun, RunError> {
+        let deadline = Deadline::new(self.cancellation_token.clone(), self.timeout);
+        let mut run = TestRun::default();
+
+        for artifact in artifacts {
+            let mut json = self.libtest_json && self.rustc_bootstrap;
+            for test in &artifact.tests {
+                let result = loop {
+                    let start = Instant::now();
+                    let output = run_output_async::<RunError>(
+                        self.command(artifact, test, json),
+                        &deadline.limit(self.test_timeout),
+                    )
+                    .await;
+                    let elapsed = start.elapsed();
+                    if let Some(result) =
+                        test_result(artifact, test, output, elapsed, &deadline, json)?
+                    {
+                        break result;
+                    }
+                    debug!(test = %test.name, "Running as JSON failed, falling back to text");
+                    json = false;
+                };
+                self.record(&mut run, result);
+            }
+        }
+
+        Ok(run)
+    }
+
+    fn command(&self, artifact: &Artifact, test: &TestFn, json: bool) -> Command {
+        let mut cmd = Command::new(&artifact.artifact.executable);
+
+        if json {
+            libtest::json_args(&mut cmd)
+                .arg("--report-time")
+                .env("RUSTC_BOOTSTRAP", "1");
+        } else {
+            cmd.arg("--format=pretty");
+        }
+        cmd.args(test.run_args())
+            // So that it prints what passing tests printed too
+            .arg("--show-output")
+            .arg("--test-threads=1")
+            .arg("--color=never")
+            .envs(artifact.artifact.build_script_env())
+            .stderr(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stdin(Stdio::null());
+        if artifact.runs_ignored() {
+            cmd.arg("--include-ignored");
+        }
+
+        if let Some(package) = &artifact.package {
+            cmd.envs(package.vars.iter().map(|(k, v)| (k, v)))
+                .current_dir(&package.dir);
+        }
+        cmd.envs(self.env.iter().map(|(k, v)| (k, v)));
+        if let Some(dir) = &self.current_dir {
+            cmd.current_dir(dir);
+        }
+
+        cmd
+    }
+
+    fn record(&mut self, run: &mut TestRun, result: TestResult) {
+        debug!(test = %result.test.name, outcome = ?result.outcome, "Ran test");
+        run.summary.record(&result);
+        if let Some(cb) = &mut self.on_result {
+            cb(result.clone());
+        }
+        run.results.push(result);
+    }
+}
+
+/// The result of running `test`, given the output of its test binary.
+/// `deadline` is for the whole run.
+///
+/// `None` if we asked for JSON and the test binary didn't understand, so we
+/// should run it again without.
+fn test_result(
+    artifact: &Artifact,
+    test: &TestFn,
+    output: Result<Output, RunError>,
+    duration: Duration,
+    deadline: &Deadline,
+    json: bool,
+) -> Result<Option<TestResult>, RunError> {
+    let (outcome, stdout, stderr, duration) = match output {
+        Ok(output) => {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let stderr = String::from_utf8_lossy(&output.stderr).into();
+            let parsed = if json {
+                parse_json(&test.name, &stdout)
+            } else {
+                parse_text(&test.name, &stdout)
+            };
+            match parsed {
+                Some((outcome, captured, exec_time)) => {
+                    (outcome, captured, stderr, exec_time.unwrap_or(duration))
+                }
+                None if json && !libtest::is_json(&stdout) => return Ok(None),
+                // It crashed before libtest could report it (e.g. it aborted)
+                None if !output.status.success() => {
+                    (TestOutcome::Failed, String::new(), stderr, duration)
+                }
+                None => return Err(RunError::Parse(stdout.into())),
+            }
+        }
+        // Only the test's own timeout, not the whole run's
+        Err(RunError::Stopped(BuildError::TimedOut(_))) if deadline.check().is_ok() => (
+            TestOutcome::TimedOut,
+            String::new(),
+            String::new(),
+            duration,
+        ),
+        Err(err) => return Err(err),
+    };
+
+    Ok(Some(TestResult {
+        package_id: artifact.artifact.package_id.clone(),
+        target: artifact.artifact.target.clone(),
+        test: test.clone(),
+        outcome,
+        stdout,
+        stderr,
+        duration,
+    }))
+}
+
+/// What libtest reported for `name` as JSON: the outcome, what it captured,
+/// and how long it says the test took
+fn parse_json(name: &str, stdout: &str) -> Option<(TestOutcome, String, Option<Duration>)> {
+    let result = libtest::parse_result(name, stdout)?;
+    let outcome = match result.event.as_str() {
+        "ok" => TestOutcome::Passed,
+        "failed" => TestOutcome::Failed,
+        "ignored" => TestOutcome::Ignored,
+        // It took longer than `--ensure-time` allows
+        "timeout" => TestOutcome::TimedOut,
+        _ => return None,
+    };
+    Some((outcome, result.stdout, result.exec_time))
+}
+
+/// What libtest reported for `name` as text: the outcome and what it captured
+fn parse_text(name: &str, stdout: &str) -> Option<(TestOutcome, String, Option<Duration>)> {
+    let outcome = parse_outcome(name, stdout)?;
+    let captured = parse_captured(name, stdout).unwrap_or_default();
+    Some((outcome, captured, None))
+}
+
+/// The outcome libtest reported for `name`, from a line like
+/// `test name ... ok` or `test name - should panic ... ok`.
+fn parse_outcome(name: &str, stdout: &str) -> Option<TestOutcome> {
+    let result = stdout.lines().find_map(|line| {
+        let rest = line.strip_prefix("test ")?.strip_prefix(name)?;
+        if !rest.starts_with(' ') {
+            // A different test whose name starts with this one's
+            return None;
+        }
+        Some(rest.split_once(" ... ")?.1)
+    })?;
+
+    match result {
+        "ok" => Some(TestOutcome::Passed),
+        "FAILED" => Some(TestOutcome::Failed),
+        "ignored" => Some(TestOutcome::Ignored),
+        // Newer versions of libtest print the reason
+        result if result.starts_with("ignored, ") => Some(TestOutcome::Ignored),
+        _ => None,
+    }
+}
+
+/// What libtest captured from `name`, which it prints as
+/// `---- name stdout ----\n{captured}\n` followed by a blank line and a list
+/// of the tests that passed or failed.
+fn parse_captured(name: &str, stdout: &str) -> Option<String> {
+    let header = format!("---- {name} stdout ----\n");
+    let start = stdout.find(&header)? + header.len();
+    let rest = &stdout[start..];
+
+    // The captured output could contain anything, so look for the list from
+    // the end
+    let end = [
+        format!("\nsuccesses:\n    {name}\n"),
+        format!("\nfailures:\n    {name}\n"),
+    ]
+    .iter()
+    .filter_map(|list| rest.rfind(list.as_str()))
+    .max()?;
+
+    let captured = &rest[..end];
+    Some(captured.strip_suffix('\n').unwrap_or(captured).to_owned())
+}
+
+/// Where `cargo test` runs a package's tests, and the environment variables
+/// it describes the package with
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct PackageEnv {
+    /// The directory with the package's `Cargo.toml`
+    pub(crate) dir: Utf8PathBuf,
+    vars: Vec<(&'static str, String)>,
+}
+
+impl PackageEnv {
+    /// The package with `id`, if `cargo metadata` listed it
+    pub(crate) fn find(metadata: &Metadata, id: &PackageId) -> Option<Self> {
+        metadata
+            .inner
+            .packages
+            .iter()
+            .find(|pkg| &pkg.id == id)
+            .map(Self::new)
+    }
+
+    fn new(package: &Package) -> Self {
+        let dir = package
+            .manifest_path
+            .parent()
+            .map_or_else(Utf8PathBuf::new, ToOwned::to_owned);
+        let version = &package.version;
+        let pre: Vec<_> = version.pre.iter().map(ToString::to_string).collect();
+        let optional = |value: Option<&str>| value.unwrap_or_default().to_owned();
+        let vars = vec![
+            ("CARGO_MANIFEST_DIR", dir.to_string()),
+            ("CARGO_MANIFEST_PATH", package.manifest_path.to_string()),
+            ("CARGO_PKG_NAME", package.name.clone()),
+            ("CARGO_PKG_VERSION", version.to_string()),
+            ("CARGO_PKG_VERSION_MAJOR", version.major.to_string()),
+            ("CARGO_PKG_VERSION_MINOR", version.minor.to_string()),
+            ("CARGO_PKG_VERSION_PATCH", version.patch.to_string()),
+            ("CARGO_PKG_VERSION_PRE", pre.join(".")),
+            ("CARGO_PKG_AUTHORS", package.authors.join(":")),
+            (
+                "CARGO_PKG_DESCRIPTION",
+                optional(package.description.as_deref()),
+            ),
+            ("CARGO_PKG_HOMEPAGE", optional(package.homepage.as_deref())),
+            (
+                "CARGO_PKG_REPOSITORY",
+                optional(package.repository.as_deref()),
+            ),
+            ("CARGO_PKG_LICENSE", optional(package.license.as_deref())),
+            (
+                "CARGO_PKG_LICENSE_FILE",
+                optional(package.license_file.as_ref().map(|path| path.as_str())),
+            ),
+        ];
+        Self { dir, vars }
+    }
+}
+
+/// Failed to run tests
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum RunError {
+    /// Stopped running tests: {0}
+    Stopped(#[from] BuildError),
+    /// Failed to execute a test binary
+    Execute(#[from] io::Error),
+    /// Failed to parse what a test binary printed. Are you using a custom test runner? Got: {0}
+    Parse(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test::{Compiler, IgnoredSpec, NameSpec, TypeSpec},
+        test_common::{init, Result},
+    };
+    use pretty_assertions::assert_eq;
+    use std::sync::{Arc, Mutex};
+
+    fn compile(name: NameSpec) -> eyre::Result<Vec<Artifact>> {
+        Ok(Compiler::new(name, TypeSpec::Lib)
+            .workspace("samples/outcomes")
+            .compile()?)
+    }
+
+    fn outcome<'a>(run: &'a TestRun, name: &str) -> &'a TestResult {
+        run.results
+            .iter()
+            .find(|result| result.test.name == format!("tests::{name}"))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_outcomes() -> Result {
+        init();
+        let artifacts = compile(NameSpec::Any)?;
+        let run = Runner::new()
+            .test_timeout(Duration::from_secs(1))
+            .run(&artifacts)?;
+
+        assert_eq!(TestOutcome::Passed, outcome(&run, "passes").outcome);
+        assert_eq!(TestOutcome::Failed, outcome(&run, "fails").outcome);
+        assert_eq!(TestOutcome::Passed, outcome(&run, "panics").outcome);
+        assert_eq!(TestOutcome::Ignored, outcome(&run, "ignored").outcome);
+        assert_eq!(
+            TestOutcome::Ignored,
+            outcome(&run, "ignored_with_reason").outcome
+        );
+        assert_eq!(TestOutcome::TimedOut, outcome(&run, "sleeps").outcome);
+        assert!(outcome(&run, "sleeps").duration >= Duration::from_secs(1));
+
+        assert_eq!(
+            TestSummary {
+                // Including in_package_dir and without_rustc_bootstrap
+                passed: 5,
+                // Including reads_env, because we didn't set the variable
+                failed: 2,
+                ignored: 2,
+                timed_out: 1,
+                duration: run.summary.duration,
+            },
+            run.summary
+        );
+        assert!(!run.summary.success());
+        Ok(())
+    }
+
+    #[test]
+    fn test_captured() -> Result {
+        init();
+        let artifacts = compile(NameSpec::Any)?;
+        let run = Runner::new()
+            .test_timeout(Duration::from_secs(1))
+            .run(&artifacts)?;
+
+        assert_eq!("to stdout\nto stderr\n", outcome(&run, "passes").stdout);
+        assert!(outcome(&run, "fails")
+            .stdout
+            .starts_with("before failing\n"));
+        assert!(outcome(&run, "fails").stdout.contains("left: 1"));
+        assert_eq!(
+            "\n\nfailures:\nno newline",
+            outcome(&run, "no_trailing_newline").stdout
+        );
+        assert_eq!("", outcome(&run, "passes").stderr);
+        Ok(())
+    }
+
+    #[test]
+    fn test_current_dir() -> Result {
+        init();
+        let artifacts = compile(NameSpec::exact("tests::in_package_dir"))?;
+        assert_eq!(1, artifacts[0].tests.len());
+
+        let run = Runner::new().run(&artifacts)?;
+        assert_eq!(TestOutcome::Passed, run.results[0].outcome);
+
+        let run = Runner::new().current_dir("samples").run(&artifacts)?;
+        assert_eq!(TestOutcome::Failed, run.results[0].outcome);
+        Ok(())
+    }
+
+    #[test]
+    fn test_env() -> Result {
+        init();
+        let artifacts = compile(NameSpec::exact("tests::reads_env"))?;
+        let run = Runner::new()
+            .env("SEACAN_TEST_ENV", "value")
+            .run(&artifacts)?;
+        assert_eq!(TestOutcome::Passed, run.results[0].outcome);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cargo_env() -> Result {
+        init();
+        let artifacts = Compiler::new(NameSpec::Any, TypeSpec::integration("runtime_env"))
+            .workspace("samples/build_script")
+            .compile()?;
+        assert_eq!(1, artifacts[0].tests.len());
+
+        let run = Runner::new().run(&artifacts)?;
+        let result = &run.results[0];
+        assert_eq!(TestOutcome::Passed, result.outcome, "{}", result.stdout);
+
+        let run = Runner::new()
+            .env("CARGO_PKG_NAME", "overridden")
+            .run(&artifacts)?;
+        assert_eq!(TestOutcome::Failed, run.results[0].outcome);
+        Ok(())
+    }
+
+    #[test]
+    fn test_on_result() -> Result {
+        init();
+        let artifacts = compile(NameSpec::substring("ignored"))?;
+        let results = Arc::new(Mutex::new(Vec::new()));
+        let run = Runner::new()
+            .on_result({
+                let results = Arc::clone(&results);
+                move |result| results.lock().unwrap().push(result)
+            })
+            .run(&artifacts)?;
+
+        assert_eq!(2, run.results.len());
+        assert_eq!(run.results, *results.lock().unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_ignored() -> Result {
+        init();
+        for ignored in [IgnoredSpec::Only, IgnoredSpec::Include] {
+            let artifacts = Compiler::new(NameSpec::substring("ignored"), TypeSpec::Lib)
+                .workspace("samples/outcomes")
+                .ignored(ignored)
+                .compile()?;
+            let run = Runner::new().run(&artifacts)?;
+            assert_eq!(2, run.summary.passed);
+            assert_eq!(0, run.summary.ignored);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_timeout() -> Result {
+        init();
+        let artifacts = compile(NameSpec::exact("tests::sleeps"))?;
+        let start = Instant::now();
+        let result = Runner::new()
+            .timeout(Duration::from_secs(1))
+            .test_timeout(Duration::from_secs(30))
+            .run(&artifacts);
+
+        assert!(
+            matches!(result, Err(RunError::Stopped(BuildError::TimedOut(_)))),
+            "{:?}",
+            result
+        );
+        assert!(start.elapsed() < Duration::from_secs(30));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cancelled() -> Result {
+        init();
+        let artifacts = compile(NameSpec::exact("tests::passes"))?;
+        let token = CancellationToken::new();
+        token.cancel();
+        let result = Runner::new().cancellation_token(token).run(&artifacts);
+        assert!(
+            matches!(result, Err(RunError::Stopped(BuildError::Cancelled))),
+            "{:?}",
+            result
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_run_async() -> Result {
+        init();
+        let artifacts = compile(NameSpec::substring("ail"))?;
+        let run = Runner::new()
+            .test_timeout(Duration::from_secs(1))
+            .run_async(&artifacts)
+            .await?;
+
+        assert_eq!(TestOutcome::Failed, outcome(&run, "fails").outcome);
+        assert_eq!(
+            "\n\nfailures:\nno newline",
+            outcome(&run, "no_trailing_newline").stdout
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_libtest_json() -> Result {
+        init();
+        let artifacts = compile(NameSpec::Any)?;
+        let run = |libtest_json| {
+            Runner::new()
+                .libtest_json(libtest_json)
+                .rustc_bootstrap(true)
+                .test_timeout(Duration::from_secs(1))
+                .run(&artifacts)
+        };
+        let (json, text) = (run(true)?, run(false)?);
+
+        let outcomes = |run: &TestRun| -> Vec<_> {
+            run.results
+                .iter()
+                .filter(|result| result.test.name != "tests::without_rustc_bootstrap")
+                .map(|result| (result.test.name.clone(), result.outcome))
+                .collect()
+        };
+        assert_eq!(outcomes(&text), outcomes(&json));
+        // We only set RUSTC_BOOTSTRAP to run tests as JSON
+        assert_eq!(
+            TestOutcome::Passed,
+            outcome(&text, "without_rustc_bootstrap").outcome
+        );
+        assert_eq!(
+            TestOutcome::Failed,
+            outcome(&json, "without_rustc_bootstrap").outcome
+        );
+        for name in &["passes", "no_trailing_newline"] {
+            assert_eq!(outcome(&text, name).stdout, outcome(&json, name).stdout);
+        }
+        assert!(outcome(&json, "fails").stdout.contains("left: 1"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_libtest_json_without_rustc_bootstrap() -> Result {
+        init();
+        let artifacts = Compiler::new(
+            NameSpec::exact("tests::without_rustc_bootstrap"),
+            TypeSpec::Lib,
+        )
+        .workspace("samples/outcomes")
+        .libtest_json(true)
+        .rustc_bootstrap(true)
+        .compile()?;
+        assert_eq!(1, artifacts[0].tests.len());
+
+        let run = Runner::new().libtest_json(true).run(&artifacts)?;
+        assert_eq!(TestOutcome::Passed, run.results[0].outcome);
+        Ok(())
+    }
+
+    #[test]
+    fn test_libtest_json_fallback() -> Result {
+        init();
+        let artifacts = Compiler::new(NameSpec::Any, TypeSpec::integration("old_libtest"))
+            .workspace("samples/outcomes")
+            .libtest_json(true)
+            .compile()?;
+        assert_eq!(1, artifacts.len());
+        assert_eq!("only_test", artifacts[0].tests[0].name);
+
+        let run = Runner::new()
+            .libtest_json(true)
+            .rustc_bootstrap(true)
+            .run(&artifacts)?;
+        assert_eq!(1, run.results.len());
+        assert_eq!(TestOutcome::Passed, run.results[0].outcome);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_json() {
+        let stdout = r#"{ "type": "test", "event": "started", "name": "a" }
+{ "type": "test", "name": "a", "event": "timeout", "exec_time": 2.5, "stdout": "slow\n" }
+"#;
+        assert_eq!(
+            Some((
+                TestOutcome::TimedOut,
+                "slow\n".to_owned(),
+                Some(Duration::from_millis(2500))
+            )),
+            parse_json("a", stdout)
+        );
+    }
+
+    #[test]
+    fn test_parse_outcome() {
+        assert_eq!(
+            Some(TestOutcome::Passed),
+            parse_outcome("a", "\nrunning 1 test\ntest a - should panic ... ok\n")
+        );
+        assert_eq!(
+            Some(TestOutcome::Ignored),
+            parse_outcome("a", "test a ... ignored, takes ... forever\n")
+        );
+        assert_eq!(None, parse_outcome("a", "test ab ... ok\n"));
+        assert_eq!(None, parse_outcome("a", "test a ... "));
+    }
+}
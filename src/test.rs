@@ -2,23 +2,33 @@
 //! Main entrypoint: [`test::Compiler`]
 
 use std::{
-    fmt,
-    io::{self, BufReader},
-    path::PathBuf,
-    process::{Command, Stdio},
+    fmt, io, mem,
+    process::{Command, ExitStatus, Output, Stdio},
 };
 
-use camino::Utf8PathBuf;
-use cargo_metadata::CompilerMessage;
+use cargo_metadata::{CompilerMessage, Message};
 use derivative::Derivative;
 use lazy_static::lazy_static;
 use regex::Regex;
-use tracing::{error, instrument, warn};
+use tracing::{debug, error, instrument, warn};
 
+#[cfg(feature = "tokio")]
+use crate::run::{run_cargo_async, run_output_async};
 use crate::{
-    handle_compiler_msg, BuildError, ExecutableArtifact, FeatureSpec, PackageSpec, MSG_FORMAT,
+    cancel::Deadline,
+    event::{handle_output_event, handle_progress_event},
+    handle_compiler_msg, libtest,
+    metadata::Metadata,
+    options::{cargo_options_builders, CargoOptions},
+    progress::handle_progress,
+    run::{run_cargo, run_output, BuildState, CargoOutput},
+    BuildError, BuildEvent, BuildOutcome, BuildSummary, BuildTimings, CompileError,
+    ExecutableArtifact, ProfileSpec, Progress,
 };
 
+use crate::runner::PackageEnv;
+pub use crate::runner::{RunError, Runner, TestOutcome, TestResult, TestRun, TestSummary};
+
 /// Compile tests
 ///
 /// ```
@@ -32,15 +42,18 @@ use crate::{
 #[derive(Derivative)]
 #[derivative(Debug)]
 pub struct Compiler {
-    target_dir: Option<Utf8PathBuf>,
-    workspace: Option<PathBuf>,
-    package: PackageSpec,
     name: NameSpec,
     test_type: TypeSpec,
     #[derivative(Debug = "ignore")]
-    on_compiler_msg: Option<Box<dyn FnMut(CompilerMessage)>>,
-    features: Option<FeatureSpec>,
-    is_release: bool,
+    on_compiler_msg: Option<Box<dyn FnMut(CompilerMessage) + Send>>,
+    #[derivative(Debug = "ignore")]
+    on_progress: Option<Box<dyn FnMut(Progress) + Send>>,
+    #[derivative(Debug = "ignore")]
+    on_event: Option<Box<dyn FnMut(BuildEvent) + Send>>,
+    ignored: IgnoredSpec,
+    libtest_json: bool,
+    list_ignored: bool,
+    opts: CargoOptions,
 }
 
 /// A compiled test artifact
@@ -52,6 +65,9 @@ pub struct Artifact {
     /// you provided.
     pub tests: Vec<TestFn>,
     name_spec: NameSpec,
+    ignored_spec: IgnoredSpec,
+    /// `None` if `cargo metadata` failed or didn't list the package
+    pub(crate) package: Option<PackageEnv>,
 }
 
 impl Artifact {
@@ -59,7 +75,14 @@ impl Artifact {
     /// only the tests and benches that match the spec you provided.
     #[must_use]
     pub fn run_args(&self) -> Vec<String> {
-        self.name_spec.run_args()
+        let mut args = self.name_spec.run_args();
+        args.extend(self.ignored_spec.run_args());
+        args
+    }
+
+    /// If running the tests should run the ignored ones too
+    pub(crate) fn runs_ignored(&self) -> bool {
+        self.ignored_spec != IgnoredSpec::Skip
     }
 }
 
@@ -71,6 +94,14 @@ pub struct TestFn {
     pub name: String,
     /// The type of the test
     pub test_type: TestFnType,
+    /// If the test is `#[ignore]`d, so it only runs if you ask for ignored
+    /// tests. `None` if we don't know, because we listed the tests as text
+    /// without [`Compiler::list_ignored`].
+    pub ignored: Option<bool>,
+    /// Why the test is ignored (i.e. `#[ignore = "reason"]`). Only available
+    /// with [`Compiler::libtest_json`], because libtest doesn't list it as
+    /// text.
+    pub ignore_reason: Option<String>,
 }
 
 impl TestFn {
@@ -139,6 +170,30 @@ impl NameSpec {
     }
 }
 
+/// Specify tests and benches based on whether they're `#[ignore]`d
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Default)]
+pub enum IgnoredSpec {
+    /// Matches every test, but ignored tests don't run (the default behavior
+    /// of `cargo test`)
+    #[default]
+    Skip,
+    /// Only ignored tests, which run (i.e. `cargo test -- --ignored`)
+    Only,
+    /// Matches every test, and ignored tests run too (i.e.
+    /// `cargo test -- --include-ignored`)
+    Include,
+}
+
+impl IgnoredSpec {
+    fn run_args(self) -> Vec<String> {
+        match self {
+            Self::Skip => vec![],
+            Self::Only => vec!["--ignored".into()],
+            Self::Include => vec!["--include-ignored".into()],
+        }
+    }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 /// Specify the type of test artifact to build
 ///
@@ -160,6 +215,10 @@ pub enum TypeSpec {
     Example(String),
     /// Unit tests defined in any example
     Examples,
+    /// Benchmarks in a bench target, run as tests (i.e. `cargo test --bench <name>`)
+    Bench(String),
+    /// Every bench target
+    Benches,
     /// Doctests
     Doc,
     /// Whatever `cargo test` tests if you specify nothing
@@ -184,6 +243,12 @@ impl TypeSpec {
     pub fn example(name: impl Into<String>) -> Self {
         Self::Example(name.into())
     }
+
+    /// Helper for [`Self::Bench`]
+    #[must_use]
+    pub fn bench(name: impl Into<String>) -> Self {
+        Self::Bench(name.into())
+    }
 }
 
 impl Compiler {
@@ -191,184 +256,491 @@ impl Compiler {
     #[must_use]
     pub fn new(name: NameSpec, test_type: TypeSpec) -> Self {
         Self {
-            workspace: None,
-            package: PackageSpec::Any,
             name,
             on_compiler_msg: None,
-            target_dir: None,
+            on_progress: None,
+            on_event: None,
+            ignored: IgnoredSpec::default(),
+            libtest_json: false,
+            list_ignored: false,
+            opts: CargoOptions::new(),
             test_type,
-            features: None,
-            is_release: false,
         }
     }
 
-    /// The directory to run cargo in.
-    ///
-    /// By default the current working directory.
-    pub fn workspace(&mut self, path: impl Into<PathBuf>) -> &mut Self {
-        self.workspace = Some(path.into());
+    cargo_options_builders!();
+
+    /// Which tests to include based on whether they're `#[ignore]`d. Defaults
+    /// to [`IgnoredSpec::Skip`].
+    pub fn ignored(&mut self, ignored: IgnoredSpec) -> &mut Self {
+        self.ignored = ignored;
         self
     }
 
-    /// The package the binary is in.
-    ///
-    /// By default [`PackageSpec::Any`].
-    pub fn package(&mut self, package: PackageSpec) -> &mut Self {
-        self.package = package;
+    /// List tests with libtest's unstable JSON output (i.e.
+    /// `-Z unstable-options --format json`) instead of parsing its terse text,
+    /// which can't represent every test name. Test binaries from stable
+    /// toolchains only accept it with [`Self::rustc_bootstrap`], which also
+    /// sets `RUSTC_BOOTSTRAP` when listing. If a test artifact doesn't
+    /// support it we fall back to terse text.
+    pub fn libtest_json(&mut self, libtest_json: bool) -> &mut Self {
+        self.libtest_json = libtest_json;
         self
     }
 
-    /// Callback for compiler messages.
-    ///
-    /// Regardless of if you specify this compiler messages will be logged at
-    /// debug level using [`tracing`].
-    pub fn on_compiler_msg(&mut self, cb: impl FnMut(CompilerMessage) + 'static) -> &mut Self {
-        self.on_compiler_msg = Some(Box::new(cb));
+    /// Find out which tests are ignored ([`TestFn::ignored`]) when we list the
+    /// tests as text, which takes listing each test artifact a second time.
+    /// We always know when listing as JSON or with [`IgnoredSpec::Only`].
+    pub fn list_ignored(&mut self, list_ignored: bool) -> &mut Self {
+        self.list_ignored = list_ignored;
         self
     }
 
-    /// Where to put the build artifacts.
+    /// Compile the described tests
     ///
-    /// By default this is whatever cargo chooses by default.
-    pub fn target_dir(&mut self, target_dir: impl Into<Utf8PathBuf>) -> &mut Self {
-        self.target_dir = Some(target_dir.into());
-        self
+    /// [`Self::timeout`] and [`Self::cancellation_token`] also cover listing
+    /// the tests, and the compile fails with [`Error::Build`].
+    ///
+    /// With [`Self::keep_going`], if some test artifacts build and others
+    /// don't we return [`Error::Partial`] with the tests in the artifacts that
+    /// did build. `cargo test` can't keep going, so for
+    /// [`TypeSpec::Integration`], [`TypeSpec::Integrations`] and
+    /// [`TypeSpec::Unspecified`] we build the same artifacts with
+    /// `cargo build --keep-going` and the profile `cargo test` would use. For
+    /// the other types cargo makes a best effort (i.e. `--no-fail-fast`).
+    ///
+    /// # Errors
+    ///
+    /// If building failed or we couldn't list the tests in one of the built
+    /// artifacts.
+    #[instrument(err)]
+    pub fn compile(&mut self) -> Result<Vec<Artifact>, Error> {
+        Ok(self.compile_outcome()?.artifacts)
     }
 
-    /// Enable or disable feature flags.
+    /// Like [`Self::compile`], but also returns the rest of what cargo
+    /// reported: diagnostics, which units were fresh, and [`Self::timings`].
     ///
-    /// By default this is whatever cargo chooses by default.
-    pub fn features(&mut self, features: FeatureSpec) -> &mut Self {
-        self.features = Some(features);
-        self
+    /// # Errors
+    ///
+    /// See [`Self::compile`].
+    #[instrument(err)]
+    pub fn compile_outcome(&mut self) -> Result<BuildOutcome<Vec<Artifact>>, Error> {
+        let deadline = self.opts.deadline();
+        let mut built = self.artifacts_ignoring_name(&deadline)?;
+        let metadata = ok_or_warn(self.opts.metadata(&deadline));
+
+        let artifacts = mem::take(&mut built.artifacts)
+            .into_iter()
+            .map(|artifact| {
+                let package = metadata
+                    .as_ref()
+                    .and_then(|metadata| PackageEnv::find(metadata, &artifact.package_id));
+                self.get_artifact_tests(artifact, package, &deadline)
+            })
+            .collect::<Result<_, _>>()?;
+
+        built.outcome(artifacts)
     }
 
-    /// If we should build in release mode.
-    pub fn release(&mut self, is_release: bool) -> &mut Self {
-        self.is_release = is_release;
-        self
+    /// Like [`Self::compile`], but doesn't block the thread.
+    ///
+    /// Cargo and the test artifacts are killed if you drop the future before
+    /// it finishes.
+    ///
+    /// # Errors
+    ///
+    /// See [`Self::compile`].
+    #[cfg(feature = "tokio")]
+    #[instrument(err)]
+    pub async fn compile_async(&mut self) -> Result<Vec<Artifact>, Error> {
+        Ok(self.compile_outcome_async().await?.artifacts)
     }
 
-    /// Compile the described tests
+    /// Like [`Self::compile_outcome`], but doesn't block the thread.
+    ///
+    /// Cargo and the test artifacts are killed if you drop the future before
+    /// it finishes.
+    ///
+    /// # Errors
+    ///
+    /// See [`Self::compile`].
+    #[cfg(feature = "tokio")]
     #[instrument(err)]
-    pub fn compile(&mut self) -> Result<Vec<Artifact>, Error> {
-        self.artifacts_ignoring_name()?
-            .into_iter()
-            .map(|artifact| self.get_artifact_tests(artifact))
-            .collect()
+    pub async fn compile_outcome_async(&mut self) -> Result<BuildOutcome<Vec<Artifact>>, Error> {
+        let deadline = self.opts.deadline();
+        let mut built = self.artifacts_ignoring_name_async(&deadline).await?;
+        let metadata = ok_or_warn(self.opts.metadata_async(&deadline).await);
+
+        let mut artifacts = Vec::with_capacity(built.artifacts.len());
+        for artifact in mem::take(&mut built.artifacts) {
+            let package = metadata
+                .as_ref()
+                .and_then(|metadata| PackageEnv::find(metadata, &artifact.package_id));
+            artifacts.push(
+                self.get_artifact_tests_async(artifact, package, &deadline)
+                    .await?,
+            );
+        }
+
+        built.outcome(artifacts)
+    }
+
+    /// The listings we need of each test binary, either as JSON or as text.
+    /// Terse text doesn't say which tests are ignored, so we only list those
+    /// separately when we need to.
+    fn listings(&self, json: bool) -> &'static [Listing] {
+        match self.ignored {
+            _ if json => &[Listing::Json],
+            IgnoredSpec::Only => &[Listing::Ignored],
+            _ if self.list_ignored => &[Listing::All, Listing::Ignored],
+            _ => &[Listing::All],
+        }
+    }
+
+    #[instrument(err)]
+    fn get_artifact_tests(
+        &self,
+        artifact: ExecutableArtifact,
+        package: Option<PackageEnv>,
+        deadline: &Deadline,
+    ) -> Result<Artifact, Error> {
+        let mut json = self.libtest_json;
+        loop {
+            let mut outputs = Vec::new();
+            for &listing in self.listings(json) {
+                let cmd = self.list_command(&artifact, package.as_ref(), listing);
+                outputs.push((listing, run_output::<Error>(cmd, deadline)?));
+            }
+            if let Some(tests) = parse_listings(outputs)? {
+                return Ok(self.artifact(artifact, package, tests));
+            }
+            json = false;
+        }
     }
 
+    /// Takes `&mut self` because holding `&self` across an await would need
+    /// the callbacks to be `Sync` for the future to be `Send`
+    #[cfg(feature = "tokio")]
     #[instrument(err)]
-    fn get_artifact_tests(&self, artifact: ExecutableArtifact) -> Result<Artifact, Error> {
-        // TODO: If json format is added use it <https://github.com/rust-lang/libtest/issues/23>
+    async fn get_artifact_tests_async(
+        &mut self,
+        artifact: ExecutableArtifact,
+        package: Option<PackageEnv>,
+        deadline: &Deadline,
+    ) -> Result<Artifact, Error> {
+        let mut json = self.libtest_json;
+        loop {
+            let mut outputs = Vec::new();
+            for &listing in self.listings(json) {
+                let cmd = self.list_command(&artifact, package.as_ref(), listing);
+                outputs.push((listing, run_output_async::<Error>(cmd, deadline).await?));
+            }
+            if let Some(tests) = parse_listings(outputs)? {
+                return Ok(self.artifact(artifact, package, tests));
+            }
+            json = false;
+        }
+    }
 
+    fn list_command(
+        &self,
+        artifact: &ExecutableArtifact,
+        package: Option<&PackageEnv>,
+        listing: Listing,
+    ) -> Command {
         let mut cmd = Command::new(&artifact.executable);
 
-        cmd.arg("--list")
-            .arg("--format=terse")
-            .args(&self.name.run_args())
+        cmd.arg("--list");
+        match listing {
+            Listing::Json => {
+                libtest::json_args(&mut cmd);
+                if self.opts.rustc_bootstrap {
+                    cmd.env("RUSTC_BOOTSTRAP", "1");
+                }
+            }
+            Listing::All => {
+                cmd.arg("--format=terse");
+            }
+            Listing::Ignored => {
+                cmd.args(["--format=terse", "--ignored"]);
+            }
+        }
+        cmd.args(self.name.run_args())
             .stderr(Stdio::piped())
             .stdout(Stdio::piped())
             .stdin(Stdio::null());
 
-        if let Some(ref workspace) = self.workspace {
-            cmd.current_dir(workspace);
+        // The same directory the tests will run in
+        if let Some(package) = package {
+            cmd.current_dir(&package.dir);
         }
 
-        let out = cmd.spawn()?.wait_with_output()?;
+        cmd
+    }
 
-        if !out.status.success() {
-            return Err(Error::Libtest(String::from_utf8_lossy(&out.stderr).into()));
+    fn artifact(
+        &self,
+        artifact: ExecutableArtifact,
+        package: Option<PackageEnv>,
+        mut tests: Vec<TestFn>,
+    ) -> Artifact {
+        if self.ignored == IgnoredSpec::Only {
+            tests.retain(|test| test.ignored == Some(true));
         }
-
-        let stdout = String::from_utf8(out.stdout).map_err(|err| {
-            error!("test binary stdout not utf-8: {}", err);
-            Error::Parse(String::from_utf8_lossy(&err.as_bytes()).into())
-        })?;
-
-        let tests = parse_libtest_stdout(&stdout)?;
-        Ok(Artifact {
+        Artifact {
             artifact,
             tests,
             name_spec: self.name.clone(),
-        })
+            ignored_spec: self.ignored,
+            package,
+        }
     }
 
     #[instrument(err)]
-    fn artifacts_ignoring_name(&mut self) -> Result<Vec<ExecutableArtifact>, BuildError> {
-        let mut cmd = Command::new("cargo");
-
-        cmd.arg("test")
-            .arg("--no-run")
-            .arg(MSG_FORMAT)
-            .args(&["--package", self.package.as_repr()])
-            .stderr(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stdin(Stdio::null());
+    fn artifacts_ignoring_name(&mut self, deadline: &Deadline) -> Result<Built, BuildError> {
+        let mut build = BuildState::new();
+        let (status, stderr) = run_cargo(self.command(), deadline, |output| {
+            self.handle_output(output, &mut build);
+        })?;
+        self.built(build, status, stderr)
+    }
 
-        if let Some(ref workspace) = self.workspace {
-            cmd.current_dir(workspace);
-        }
+    #[cfg(feature = "tokio")]
+    #[instrument(err)]
+    async fn artifacts_ignoring_name_async(
+        &mut self,
+        deadline: &Deadline,
+    ) -> Result<Built, BuildError> {
+        let mut build = BuildState::new();
+        let (status, stderr) = run_cargo_async(self.command(), deadline, |output| {
+            self.handle_output(output, &mut build);
+        })
+        .await?;
+        self.built(build, status, stderr)
+    }
 
-        if let Some(features) = &self.features {
-            cmd.args(&features.to_args());
-        }
+    /// If we use `cargo build --keep-going` instead of `cargo test --no-run`.
+    /// `cargo test --no-fail-fast` finishes what it's already building after
+    /// a compile error, but doesn't start anything else.
+    fn build_keep_going(&self) -> bool {
+        self.opts.keep_going
+            && matches!(
+                self.test_type,
+                TypeSpec::Integration(_) | TypeSpec::Integrations | TypeSpec::Unspecified
+            )
+    }
 
-        if self.is_release {
-            cmd.arg("--release");
-        }
+    fn command(&self) -> Command {
+        let build_keep_going = self.build_keep_going();
 
-        if let Some(ref target_dir) = self.target_dir {
-            cmd.args(&["--target-dir", target_dir.as_str()]);
-        }
+        let mut cmd = if build_keep_going {
+            let mut cmd = self.opts.command("build");
+            if let ProfileSpec::Default = self.opts.profile {
+                // What `cargo test` uses, rather than `dev`
+                cmd.args(["--profile", "test"]);
+            }
+            cmd
+        } else {
+            let mut cmd = self.opts.command("test");
+            cmd.arg("--no-run");
+            cmd
+        };
 
         match &self.test_type {
+            // The targets `cargo test` builds: every target with `test = true`
+            // as tests, and the examples to check they compile
+            TypeSpec::Unspecified if build_keep_going => cmd.args(["--tests", "--examples"]),
             TypeSpec::Lib => cmd.arg("--lib"),
-            TypeSpec::Bin(name) => cmd.args(&["--bin", name]),
+            TypeSpec::Bin(name) => cmd.args(["--bin", name]),
             TypeSpec::Bins => cmd.arg("--bins"),
-            TypeSpec::Integration(name) => cmd.args(&["--test", name]),
-            TypeSpec::Integrations => cmd.args(&["--test", "*"]),
+            TypeSpec::Integration(name) => cmd.args(["--test", name]),
+            TypeSpec::Integrations => cmd.args(["--test", "*"]),
             TypeSpec::Doc => cmd.arg("--doc"),
-            TypeSpec::Example(name) => cmd.args(&["--example", name]),
+            TypeSpec::Example(name) => cmd.args(["--example", name]),
             TypeSpec::Examples => cmd.arg("--examples"),
+            TypeSpec::Bench(name) => cmd.args(["--bench", name]),
+            TypeSpec::Benches => cmd.arg("--benches"),
             TypeSpec::Unspecified => &mut cmd,
         };
 
-        let mut cmd = cmd.spawn()?;
-
-        let stdout = cmd.stdout.take().unwrap();
-        let stderr = cmd.stderr.take().unwrap();
+        cmd
+    }
 
-        let mut artifacts = Vec::new();
+    fn handle_output(&mut self, output: CargoOutput, build: &mut BuildState<ExecutableArtifact>) {
+        handle_output_event(&output, &mut self.on_event);
+        if let Some(progress) = build.progress.record(&output) {
+            handle_progress_event(&progress, &mut self.on_event);
+            handle_progress(progress, &mut self.on_progress);
+        }
+        if let CargoOutput::Message(msg) = output {
+            self.handle_msg(msg, build);
+        }
+    }
 
-        let messages = cargo_metadata::Message::parse_stream(BufReader::new(stdout));
-        for msg in messages {
-            match msg? {
-                cargo_metadata::Message::CompilerMessage(msg) => {
-                    handle_compiler_msg(msg, &mut self.on_compiler_msg)
+    fn handle_msg(&mut self, msg: Message, build: &mut BuildState<ExecutableArtifact>) {
+        build.record(&msg);
+        match msg {
+            Message::CompilerMessage(msg) => {
+                handle_compiler_msg(msg, &mut self.on_compiler_msg);
+            }
+            Message::CompilerArtifact(art) => {
+                if !art.profile.test {
+                    // cargo --test builds binaries so that integration tests can run them.
+                    // See <https://github.com/rust-lang/cargo/issues/7958>
+                    return;
                 }
-                cargo_metadata::Message::CompilerArtifact(art) => {
-                    if !art.profile.test {
-                        // cargo --test builds binaries so that integration tests can run them.
-                        // See <https://github.com/rust-lang/cargo/issues/7958>
-                        continue;
-                    }
-                    if let Some(art) = ExecutableArtifact::maybe_from(art) {
-                        artifacts.push(art);
-                    }
+                if let Some(art) = ExecutableArtifact::maybe_from(
+                    art,
+                    self.opts.profile.name("test"),
+                    self.opts.target_triple.clone(),
+                    &build.build_scripts,
+                ) {
+                    build.artifacts.push(art);
                 }
-                _ => {}
             }
+            _ => {}
         }
+    }
 
-        if cmd.wait()?.success() {
-            Ok(artifacts)
+    fn built(
+        &self,
+        build: BuildState<ExecutableArtifact>,
+        status: ExitStatus,
+        stderr: String,
+    ) -> Result<Built, BuildError> {
+        let BuildState {
+            artifacts, summary, ..
+        } = build;
+
+        if status.success() {
+            Ok(Built {
+                artifacts,
+                error: None,
+                summary,
+                timings: self.opts.read_timings(&stderr)?,
+            })
+        } else if self.opts.keep_going && !artifacts.is_empty() {
+            Ok(Built {
+                artifacts,
+                error: Some(summary.error(stderr)),
+                summary,
+                timings: None,
+            })
         } else {
-            Err(BuildError::from_stderr(stderr))
+            Err(summary.error(stderr))
         }
     }
 }
 
+/// We only need `cargo metadata` for where to run the tests and the
+/// environment variables cargo sets, which isn't worth failing the build over
+fn ok_or_warn(metadata: Result<Metadata, BuildError>) -> Option<Metadata> {
+    metadata
+        .map_err(|err| warn!(%err, "Couldn't find the tested packages"))
+        .ok()
+}
+
+/// What `cargo test --no-run` built. With keep going this can be some
+/// artifacts and an error.
+struct Built {
+    artifacts: Vec<ExecutableArtifact>,
+    error: Option<BuildError>,
+    summary: BuildSummary,
+    timings: Option<BuildTimings>,
+}
+
+impl Built {
+    /// The outcome once we've listed the tests in each artifact
+    fn outcome(self, artifacts: Vec<Artifact>) -> Result<BuildOutcome<Vec<Artifact>>, Error> {
+        match self.error {
+            None => Ok(self.summary.into_outcome(artifacts, self.timings)),
+            Some(error) => Err(Error::Partial(PartialBuild {
+                artifacts,
+                failed: self.summary.into_errors(),
+                error,
+            })),
+        }
+    }
+}
+
+/// One way of listing the tests in a test binary
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Listing {
+    /// Every test as JSON, which says which tests are ignored
+    Json,
+    /// Every test as terse text
+    All,
+    /// Only the ignored tests as terse text
+    Ignored,
+}
+
+/// The tests listed as terse text
+fn parse_list(out: Output) -> Result<Vec<TestFn>, Error> {
+    if !out.status.success() {
+        return Err(Error::Libtest(String::from_utf8_lossy(&out.stderr).into()));
+    }
+    parse_libtest_stdout(&list_stdout(out)?)
+}
+
+/// The tests in a test binary, given what each of [`Compiler::listings`]
+/// printed. `None` if the test binary didn't understand JSON, so we should list
+/// the tests as text instead.
+fn parse_listings(outputs: Vec<(Listing, Output)>) -> Result<Option<Vec<TestFn>>, Error> {
+    let mut all = None;
+    let mut ignored = None;
+    for (listing, out) in outputs {
+        match listing {
+            Listing::Json => return parse_json_list(out),
+            Listing::All => all = Some(parse_list(out)?),
+            Listing::Ignored => ignored = Some(parse_list(out)?),
+        }
+    }
+
+    let tests = match (all, ignored) {
+        (Some(mut all), Some(ignored)) => {
+            for test in &mut all {
+                test.ignored = Some(ignored.iter().any(|ignored| ignored.name == test.name));
+            }
+            all
+        }
+        (Some(all), None) => all,
+        (None, ignored) => {
+            let mut ignored = ignored.unwrap_or_default();
+            for test in &mut ignored {
+                test.ignored = Some(true);
+            }
+            ignored
+        }
+    };
+    Ok(Some(tests))
+}
+
+/// The tests listed as JSON, or `None` if the test artifact rejected the
+/// options and we should list them as terse text instead
+fn parse_json_list(out: Output) -> Result<Option<Vec<TestFn>>, Error> {
+    if !out.status.success() {
+        debug!(
+            stderr = %String::from_utf8_lossy(&out.stderr),
+            "Listing tests as JSON failed, falling back to terse"
+        );
+        return Ok(None);
+    }
+    let stdout = list_stdout(out)?;
+    match libtest::parse_list(&stdout) {
+        Some(tests) => Ok(Some(tests)),
+        // Older versions of libtest ignore the format when listing
+        None => parse_libtest_stdout(&stdout).map(Some),
+    }
+}
+
+fn list_stdout(out: Output) -> Result<String, Error> {
+    String::from_utf8(out.stdout).map_err(|err| {
+        error!("test binary stdout not utf-8: {}", err);
+        Error::Parse(String::from_utf8_lossy(err.as_bytes()).into())
+    })
+}
+
 #[instrument(err)]
 fn parse_libtest_stdout(stdout: &str) -> Result<Vec<TestFn>, Error> {
     // See libtest::list_tests_console
@@ -394,16 +766,55 @@ fn parse_libtest_stdout(stdout: &str) -> Result<Vec<TestFn>, Error> {
             }
         };
 
-        tests.push(TestFn { name, test_type });
+        tests.push(TestFn {
+            name,
+            test_type,
+            ignored: None,
+            ignore_reason: None,
+        });
     }
     Ok(tests)
 }
 
+/// Some test artifacts were built, but others failed to build.
+///
+/// Only returned if you enable [`Compiler::keep_going`].
+#[derive(Debug)]
+pub struct PartialBuild {
+    /// The tests in the artifacts that did build
+    pub artifacts: Vec<Artifact>,
+    /// The errors the compiler reported, each with the target that failed to
+    /// build. A target can have several.
+    ///
+    /// Targets that were skipped because something they depend on failed
+    /// aren't included.
+    pub failed: Vec<CompileError>,
+    /// Why the build failed
+    pub error: BuildError,
+}
+
+impl fmt::Display for PartialBuild {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut failed: Vec<_> = self.failed.iter().map(|e| e.target.name.as_str()).collect();
+        failed.sort_unstable();
+        failed.dedup();
+        write!(
+            f,
+            "built {} test artifacts, failed to build [{}]: {}",
+            self.artifacts.len(),
+            failed.join(", "),
+            self.error
+        )
+    }
+}
+
 /// Failed to build tests
 #[derive(Debug, thiserror::Error, displaydoc::Display)]
 pub enum Error {
     /// Failed to build
     Build(#[from] BuildError),
+    /// Failed to build some tests, {0}
+    Partial(PartialBuild),
     /// Failed to execute `<test_binary> --list`
     Execute(#[from] io::Error),
     /// `<test_binary> --list` returned failure. Are you using a custom test runner? Stderr: {0}
@@ -417,8 +828,28 @@ mod tests {
     // TODO: Use assert_matches! when stable
 
     use super::*;
-    use crate::test_common::{init, Result};
+    use crate::{
+        test_common::{host_triple, init, Result},
+        CargoSpec, DiagnosticLevel, FeatureSpec, PackageSpec,
+    };
     use pretty_assertions::assert_eq;
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    #[test]
+    fn test_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<Compiler>();
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn test_compile_async_send() {
+        fn assert_send<T: Send>(_: &T) {}
+        assert_send(&Compiler::new(NameSpec::Any, TypeSpec::Unspecified).compile_async());
+    }
 
     #[test]
     fn test_disabled_feature() -> Result {
@@ -486,6 +917,20 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_profile_named() -> Result {
+        init();
+        let artifact = Compiler::new(NameSpec::Any, TypeSpec::bin("hello_world"))
+            .workspace("samples/hello_world")
+            .profile(ProfileSpec::named("dist"))
+            .compile()?
+            .pop()
+            .unwrap();
+        assert_eq!("dist", artifact.artifact.profile_name);
+        assert_ne!("0", artifact.artifact.profile.opt_level);
+        Ok(())
+    }
+
     #[test]
     fn test_release_default() -> Result {
         init();
@@ -495,6 +940,241 @@ mod tests {
             .pop()
             .unwrap();
         assert_eq!("0", artifact.artifact.profile.opt_level);
+        assert_eq!("test", artifact.artifact.profile_name);
+        Ok(())
+    }
+
+    #[test]
+    fn test_config() -> Result {
+        init();
+        let artifact = Compiler::new(NameSpec::Any, TypeSpec::bin("hello_world"))
+            .workspace("samples/hello_world")
+            .config("profile.test.opt-level", 1)
+            .config("build.rustflags", vec!["--cfg", "seacan_test_cfg"])
+            .compile()?
+            .pop()
+            .unwrap();
+        assert_eq!("1", artifact.artifact.profile.opt_level);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cargo_path() -> Result {
+        init();
+        let artifacts = Compiler::new(NameSpec::Any, TypeSpec::Lib)
+            .workspace("samples/hello_world")
+            .cargo(CargoSpec::path(env!("CARGO")))
+            .compile()?;
+        assert_eq!(1, artifacts.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_keep_going() {
+        init();
+        let result = Compiler::new(NameSpec::Any, TypeSpec::Integrations)
+            .workspace("samples/keep_going")
+            .keep_going(true)
+            .jobs(1)
+            .config("profile.test.opt-level", 1)
+            .compile();
+        let partial = match result {
+            Err(Error::Partial(partial)) => partial,
+            other => panic!("Expected partial build, got {:?}", other),
+        };
+        assert_eq!(1, partial.artifacts.len());
+        assert_eq!("builds", partial.artifacts[0].artifact.target.name);
+        // Built with the test profile, like `cargo test`
+        assert_eq!("1", partial.artifacts[0].artifact.profile.opt_level);
+        assert_eq!("test_in_builds", partial.artifacts[0].tests[0].name);
+        assert_eq!(1, partial.failed.len());
+        assert_eq!("broken", partial.failed[0].target.name);
+        assert_eq!(DiagnosticLevel::Error, partial.failed[0].diagnostic.level);
+    }
+
+    #[test]
+    fn test_keep_going_unspecified() {
+        init();
+        let result = Compiler::new(NameSpec::Any, TypeSpec::Unspecified)
+            .workspace("samples/keep_going")
+            .keep_going(true)
+            .compile();
+        let partial = match result {
+            Err(Error::Partial(partial)) => partial,
+            other => panic!("Expected partial build, got {:?}", other),
+        };
+        let mut built: Vec<_> = partial
+            .artifacts
+            .iter()
+            .map(|artifact| artifact.artifact.target.name.as_str())
+            .collect();
+        built.sort_unstable();
+        assert_eq!(vec!["builds", "keep_going"], built);
+        assert!(partial
+            .artifacts
+            .iter()
+            .all(|artifact| artifact.artifact.profile_name == "test"));
+    }
+
+    #[test]
+    fn test_keep_going_default() {
+        init();
+        let result = Compiler::new(NameSpec::Any, TypeSpec::Integrations)
+            .workspace("samples/keep_going")
+            .compile();
+        assert!(matches!(result, Err(Error::Build(_))));
+    }
+
+    #[test]
+    fn test_offline() {
+        init();
+        let result = Compiler::new(NameSpec::Any, TypeSpec::Unspecified)
+            .workspace("samples/offline")
+            .offline(true)
+            .compile();
+        assert!(matches!(result, Err(Error::Build(BuildError::Offline(_)))));
+    }
+
+    #[test]
+    fn test_build_script() -> Result {
+        init();
+        let artifacts = Compiler::new(NameSpec::Any, TypeSpec::Unspecified)
+            .workspace("samples/build_script")
+            .compile()?;
+        assert_eq!(3, artifacts.len());
+        for artifact in artifacts {
+            let script = artifact.artifact.build_script.unwrap();
+            assert!(script.out_dir.join("generated.txt").is_file());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_events() -> Result {
+        init();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let artifacts = Compiler::new(NameSpec::Any, TypeSpec::integration("integration_tests_1"))
+            .workspace("samples/hello_world")
+            .on_event({
+                let events = Arc::clone(&events);
+                move |event| events.lock().unwrap().push(event)
+            })
+            .compile()?;
+        assert_eq!(1, artifacts.len());
+
+        let events = events.lock().unwrap();
+        // The lib and bins the integration test can use aren't test artifacts,
+        // but they're still events
+        let built: Vec<_> = events
+            .iter()
+            .filter_map(|event| match event {
+                BuildEvent::CompilerArtifact(art) => Some(art),
+                _ => None,
+            })
+            .collect();
+        assert!(built.iter().any(|art| !art.profile.test));
+        assert!(built.iter().any(|art| art.profile.test));
+        // Stderr can come after, but it's the last message on stdout
+        assert!(matches!(
+            events.iter().rfind(|e| !matches!(e, BuildEvent::Stderr(_))),
+            Some(BuildEvent::BuildFinished(finished)) if finished.success
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_timings() -> Result {
+        init();
+        let outcome = Compiler::new(NameSpec::Any, TypeSpec::Lib)
+            .workspace("samples/hello_world")
+            .timings(true)
+            .compile_outcome()?;
+        let timings = outcome.timings.unwrap();
+        assert!(timings
+            .units
+            .iter()
+            .any(|unit| unit.package == "hello_world"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_compile_error() {
+        init();
+        let result = Compiler::new(NameSpec::Any, TypeSpec::integration("broken"))
+            .workspace("samples/keep_going")
+            .compile();
+        let errors = match result {
+            Err(Error::Build(BuildError::Compile { errors, .. })) => errors,
+            other => panic!("Expected compile error, got {:?}", other),
+        };
+        assert_eq!(1, errors.len());
+        assert_eq!("broken", errors[0].target.name);
+        assert_eq!(DiagnosticLevel::Error, errors[0].diagnostic.level);
+        assert_eq!(
+            Some("E0308"),
+            errors[0].diagnostic.code.as_ref().map(|c| c.code.as_str())
+        );
+    }
+
+    #[test]
+    fn test_timeout_listing() {
+        init();
+        // Long enough to build, but the test binary's `--list` never finishes
+        let timeout = Duration::from_secs(20);
+        let start = std::time::Instant::now();
+        let result = Compiler::new(NameSpec::Any, TypeSpec::integration("slow_list"))
+            .workspace("samples/slow")
+            .target_dir("target/seacan_test_timeout_listing")
+            .timeout(timeout)
+            .compile();
+        assert!(
+            matches!(result, Err(Error::Build(BuildError::TimedOut(actual))) if actual == timeout),
+            "{:?}",
+            result
+        );
+        assert!(start.elapsed() < Duration::from_secs(50));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_compile_async() -> Result {
+        init();
+        let mut artifacts = Compiler::new(NameSpec::Any, TypeSpec::Integrations)
+            .workspace("samples/keep_going")
+            .keep_going(true)
+            .compile_async()
+            .await;
+        let partial = match artifacts {
+            Err(Error::Partial(partial)) => partial,
+            other => panic!("Expected partial build, got {:?}", other),
+        };
+        assert_eq!("test_in_builds", partial.artifacts[0].tests[0].name);
+
+        artifacts = Compiler::new(NameSpec::Any, TypeSpec::Lib)
+            .workspace("samples/hello_world")
+            .compile_async()
+            .await;
+        assert!(!artifacts?[0].tests.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_target_triple() -> Result {
+        init();
+        let triple = host_triple();
+        let triple = triple.as_str();
+        let artifact = Compiler::new(NameSpec::Any, TypeSpec::bin("hello_world"))
+            .workspace("samples/hello_world")
+            .target(triple)
+            .compile()?
+            .pop()
+            .unwrap();
+        assert_eq!(Some(triple), artifact.artifact.target_triple.as_deref());
+        assert!(artifact
+            .artifact
+            .executable
+            .ancestors()
+            .any(|dir| dir.file_name() == Some(triple)));
         Ok(())
     }
 
@@ -709,6 +1389,125 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_libtest_json() -> Result {
+        init();
+
+        let list = |libtest_json| -> eyre::Result<Vec<TestFn>> {
+            let mut artifacts = Compiler::new(NameSpec::substring("test_in"), TypeSpec::Lib)
+                .workspace("samples/hello_world")
+                .libtest_json(libtest_json)
+                .rustc_bootstrap(libtest_json)
+                .list_ignored(true)
+                .compile()?;
+            assert_eq!(1, artifacts.len());
+            let mut tests = artifacts.pop().unwrap().tests;
+            tests.sort_by(|a, b| a.name.cmp(&b.name));
+            Ok(tests)
+        };
+
+        let tests = list(true)?;
+        assert_eq!(4, tests.len());
+        assert_eq!(list(false)?, tests);
+
+        Ok(())
+    }
+
+    fn list_outcomes(ignored: IgnoredSpec, libtest_json: bool) -> eyre::Result<Artifact> {
+        let mut artifacts = Compiler::new(NameSpec::substring("tests::"), TypeSpec::Lib)
+            .workspace("samples/outcomes")
+            .ignored(ignored)
+            .list_ignored(true)
+            .libtest_json(libtest_json)
+            .rustc_bootstrap(libtest_json)
+            .compile()?;
+        assert_eq!(1, artifacts.len());
+        Ok(artifacts.pop().unwrap())
+    }
+
+    fn ignored_tests(artifact: &Artifact) -> Vec<(&str, Option<&str>)> {
+        artifact
+            .tests
+            .iter()
+            .filter(|test| test.ignored == Some(true))
+            .map(|test| (test.name.as_str(), test.ignore_reason.as_deref()))
+            .collect()
+    }
+
+    #[test]
+    fn test_ignored() -> Result {
+        init();
+
+        let artifact = list_outcomes(IgnoredSpec::Skip, false)?;
+        assert_eq!(10, artifact.tests.len());
+        assert_eq!(
+            vec![
+                ("tests::ignored", None),
+                ("tests::ignored_with_reason", None)
+            ],
+            ignored_tests(&artifact)
+        );
+        assert_eq!(vec!["tests::"], artifact.run_args());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ignored_unknown() -> Result {
+        init();
+
+        let artifacts = Compiler::new(NameSpec::substring("tests::"), TypeSpec::Lib)
+            .workspace("samples/outcomes")
+            .compile()?;
+        assert_eq!(10, artifacts[0].tests.len());
+        assert!(artifacts[0].tests.iter().all(|test| test.ignored.is_none()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ignored_json() -> Result {
+        init();
+
+        let artifact = list_outcomes(IgnoredSpec::Skip, true)?;
+        assert_eq!(10, artifact.tests.len());
+        assert_eq!(
+            vec![
+                ("tests::ignored", None),
+                ("tests::ignored_with_reason", Some("too slow"))
+            ],
+            ignored_tests(&artifact)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ignored_only() -> Result {
+        init();
+
+        for libtest_json in [false, true] {
+            let artifact = list_outcomes(IgnoredSpec::Only, libtest_json)?;
+            assert_eq!(2, artifact.tests.len());
+            assert_eq!(2, ignored_tests(&artifact).len());
+            assert_eq!(vec!["tests::", "--ignored"], artifact.run_args());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_include_ignored() -> Result {
+        init();
+
+        let artifact = list_outcomes(IgnoredSpec::Include, false)?;
+        assert_eq!(10, artifact.tests.len());
+        assert_eq!(2, ignored_tests(&artifact).len());
+        assert_eq!(vec!["tests::", "--include-ignored"], artifact.run_args());
+
+        Ok(())
+    }
+
     #[test]
     fn test_multiple_artifacts() -> Result {
         init();
@@ -731,6 +1530,23 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_manifest_path() -> Result {
+        init();
+
+        let mut artifacts = Compiler::new(NameSpec::substring("test_in_lib_2"), TypeSpec::Lib)
+            .manifest_path("samples/hello_world/Cargo.toml")
+            .compile()?;
+
+        assert_eq!(1, artifacts.len());
+        let mut artifact = artifacts.pop().unwrap();
+        assert_eq!(1, artifact.tests.len());
+        let test = artifact.tests.pop().unwrap();
+        assert_eq!("test_in_lib_2", test.name);
+
+        Ok(())
+    }
+
     #[test]
     fn test_ws_member() -> Result {
         init();
@@ -1,4 +1,4 @@
-use std::sync::Once;
+use std::{process::Command, sync::Once};
 
 use tracing::Level;
 
@@ -17,3 +17,28 @@ pub fn init() {
 }
 
 pub type Result = eyre::Result<()>;
+
+/// The host's target triple, from `rustc -vV`
+pub fn host_triple() -> String {
+    let out = Command::new("rustc").arg("-vV").output().unwrap();
+    String::from_utf8(out.stdout)
+        .unwrap()
+        .lines()
+        .find_map(|line| line.strip_prefix("host: "))
+        .expect("rustc -vV prints the host")
+        .to_owned()
+}
+
+/// If the rustup toolchain `name` is installed. Prints why if not, so tests
+/// that need it can skip.
+pub fn has_toolchain(name: &str) -> bool {
+    let installed = Command::new("rustup")
+        .args(["run", name, "cargo", "--version"])
+        .env("RUSTUP_AUTO_INSTALL", "0")
+        .output()
+        .is_ok_and(|out| out.status.success());
+    if !installed {
+        eprintln!("Skipping, the rustup toolchain `{name}` isn't installed");
+    }
+    installed
+}
@@ -0,0 +1,203 @@
+//! Cargo's `--timings` report
+//!
+//! Cargo used to print the timings as JSON with `--timings=json`, but no
+//! longer accepts it. All it has is the HTML report, so we read the data the
+//! report embeds for its charts. That's not a stable format: if a version of
+//! cargo stops embedding it the way we expect, we return no units or no
+//! concurrency rather than failing the build.
+
+use std::{fs, time::Duration};
+
+use camino::Utf8PathBuf;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{de::DeserializeOwned, Deserialize};
+use tracing::{instrument, warn};
+
+use crate::BuildError;
+
+/// How long a build took, from cargo's `--timings` report
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct BuildTimings {
+    /// Every unit cargo compiled or found fresh, in the order cargo finished
+    /// them. Empty if we couldn't find them in the report.
+    pub units: Vec<UnitTiming>,
+    /// How many units were compiling, waiting on dependencies, or waiting on
+    /// a job slot over the course of the build. Empty if we couldn't find it
+    /// in the report.
+    pub concurrency: Vec<Concurrency>,
+    /// The HTML report cargo wrote
+    pub report: Utf8PathBuf,
+}
+
+/// How long one unit (e.g. the lib of a package, or its build script) took
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub struct UnitTiming {
+    /// The name of the package
+    pub package: String,
+    /// The version of the package
+    pub version: String,
+    /// Cargo's description of the target, empty for a lib (e.g.
+    /// `hello_world "bin"` or `build-script`)
+    pub target: String,
+    /// The features the unit was compiled with
+    pub features: Vec<String>,
+    /// When the unit started, relative to the start of the build
+    pub start: Duration,
+    /// How long the unit took
+    pub duration: Duration,
+    /// How long until the unit's metadata (`.rmeta`) was ready, which is when
+    /// dependent units can start compiling. `None` if it has no metadata.
+    pub rmeta_time: Option<Duration>,
+}
+
+impl UnitTiming {
+    /// How long the unit took after its metadata was ready (i.e. codegen and
+    /// linking)
+    #[must_use]
+    pub fn codegen_time(&self) -> Option<Duration> {
+        self.rmeta_time
+            .map(|rmeta| self.duration.saturating_sub(rmeta))
+    }
+}
+
+/// The state of the build at a point in time
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub struct Concurrency {
+    /// Relative to the start of the build
+    pub time: Duration,
+    /// The number of units compiling
+    pub active: usize,
+    /// The number of units ready to compile but waiting for a job slot
+    pub waiting: usize,
+    /// The number of units waiting on their dependencies
+    pub inactive: usize,
+}
+
+#[derive(Deserialize)]
+struct RawUnit {
+    name: String,
+    version: String,
+    target: String,
+    #[serde(default)]
+    features: Vec<String>,
+    start: f64,
+    duration: f64,
+    /// Older versions of cargo
+    rmeta_time: Option<f64>,
+    /// Newer versions of cargo. The frontend section ends when metadata is
+    /// ready.
+    #[serde(default)]
+    sections: Option<Vec<(String, RawSection)>>,
+}
+
+#[derive(Deserialize)]
+struct RawSection {
+    end: f64,
+}
+
+#[derive(Deserialize)]
+struct RawConcurrency {
+    t: f64,
+    active: usize,
+    waiting: usize,
+    inactive: usize,
+}
+
+impl BuildTimings {
+    /// Read the report cargo mentions in its stderr
+    #[instrument(skip(stderr), err)]
+    pub(crate) fn from_stderr(stderr: &str) -> Result<Self, BuildError> {
+        lazy_static! {
+            static ref REPORT_RE: Regex =
+                Regex::new(r"Timing report saved to (?P<p>.*\.html)").unwrap();
+        }
+
+        let report = REPORT_RE
+            .captures(stderr)
+            .map(|caps| Utf8PathBuf::from(caps.name("p").unwrap().as_str()))
+            .ok_or_else(|| BuildError::Timings("cargo didn't write a report".to_owned()))?;
+
+        let html = fs::read_to_string(&report)
+            .map_err(|err| BuildError::Timings(format!("reading {report}: {err}")))?;
+
+        let units = parse_const::<Vec<RawUnit>>(&html, "UNIT_DATA")?
+            .into_iter()
+            .map(|unit| {
+                let rmeta_time = unit.rmeta_time.or_else(|| {
+                    unit.sections
+                        .as_ref()?
+                        .iter()
+                        .find(|(name, _)| name == "frontend")
+                        .map(|(_, section)| section.end)
+                });
+                UnitTiming {
+                    package: unit.name,
+                    version: unit.version,
+                    target: unit.target.trim().to_owned(),
+                    features: unit.features,
+                    start: secs(unit.start),
+                    duration: secs(unit.duration),
+                    rmeta_time: rmeta_time.map(secs),
+                }
+            })
+            .collect();
+
+        let concurrency = parse_const::<Vec<RawConcurrency>>(&html, "CONCURRENCY_DATA")?
+            .into_iter()
+            .map(|point| Concurrency {
+                time: secs(point.t),
+                active: point.active,
+                waiting: point.waiting,
+                inactive: point.inactive,
+            })
+            .collect();
+
+        Ok(Self {
+            units,
+            concurrency,
+            report,
+        })
+    }
+}
+
+/// The report embeds its data as JSON in `const NAME = ...;` statements. If
+/// it doesn't have `name` we return the default (i.e. nothing).
+fn parse_const<T: DeserializeOwned + Default>(html: &str, name: &str) -> Result<T, BuildError> {
+    let start = format!("const {name} = ");
+    let Some(json) = html
+        .find(&start)
+        .map(|idx| &html[idx + start.len()..])
+        .and_then(|rest| rest.find(";\n").map(|end| &rest[..end]))
+    else {
+        warn!("Cargo's timing report has no {}", name);
+        return Ok(T::default());
+    };
+    serde_json::from_str(json).map_err(|err| BuildError::Timings(format!("parsing {name}: {err}")))
+}
+
+fn secs(secs: f64) -> Duration {
+    Duration::from_secs_f64(secs.max(0.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_common::Result;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_parse_const() -> Result {
+        let html = "<script>\nconst CONCURRENCY_DATA = [{\"t\": 0.5, \"active\": 1, \"waiting\": 0, \"inactive\": 2}];\n</script>\n";
+        let concurrency: Vec<RawConcurrency> = parse_const(html, "CONCURRENCY_DATA")?;
+        assert_eq!(1, concurrency.len());
+        assert_eq!(2, concurrency[0].inactive);
+
+        let units: Vec<RawUnit> = parse_const(html, "UNIT_DATA")?;
+        assert!(units.is_empty());
+        Ok(())
+    }
+}
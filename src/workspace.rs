@@ -0,0 +1,531 @@
+use std::{fs, path::PathBuf};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use cargo_metadata::PackageId;
+use serde::Deserialize;
+use tracing::instrument;
+
+use crate::{
+    bin,
+    library::{self, CrateType},
+    metadata::Metadata,
+    options::CargoOptions,
+    test::{self, NameSpec, TypeSpec},
+    BuildError, FeatureSpec, PackageSpec,
+};
+
+/// The members of a workspace, from `cargo metadata`
+///
+/// ```
+/// # use seacan::workspace::Workspace;
+/// let workspace = Workspace::load("samples/hello_world")?;
+/// let package = workspace.package("hello_world").unwrap();
+/// for bin in package.bins() {
+///     let artifact = bin.bin_compiler().unwrap().compile()?;
+///     assert_eq!(bin.name, artifact.target.name);
+/// }
+/// # Ok::<_, seacan::BuildError>(())
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Workspace {
+    /// The directory of the workspace's root `Cargo.toml`
+    pub root: Utf8PathBuf,
+    /// Where cargo puts build artifacts unless you specify a target dir
+    pub target_directory: Utf8PathBuf,
+    /// Every package in the workspace, in the order cargo lists them
+    pub members: Vec<Package>,
+}
+
+/// A package in a [`Workspace`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Package {
+    /// The full ID of the package
+    pub id: PackageId,
+    /// The name of the package
+    pub name: String,
+    /// The version of the package
+    pub version: String,
+    /// The package's `Cargo.toml`
+    pub manifest_path: Utf8PathBuf,
+    /// The binary `cargo run` picks if there are several (i.e. the
+    /// `default-run` manifest key)
+    pub default_run: Option<String>,
+    /// The features the package defines
+    pub features: Vec<String>,
+    /// Everything cargo can build in the package, in the order cargo lists
+    /// them
+    pub targets: Vec<Target>,
+}
+
+/// Something cargo can build in a [`Package`] (e.g. its lib, or one of its
+/// bins)
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Target {
+    /// The name of the target. For a lib this is the crate name, so dashes in
+    /// the package name become underscores.
+    pub name: String,
+    /// What kind of target it is
+    pub kind: TargetKind,
+    /// The root source file
+    pub src_path: Utf8PathBuf,
+    /// The features that must be enabled to build it (i.e. the
+    /// `required-features` manifest key)
+    pub required_features: Vec<String>,
+    /// If `cargo test` tests it unless you ask for it specifically (i.e. the
+    /// `test` manifest key)
+    pub test: bool,
+    /// If its tests use libtest (i.e. the `harness` manifest key). If not,
+    /// [`test::Compiler`] can't list its tests.
+    pub harness: bool,
+    /// If `cargo test` runs its doctests (i.e. the `doctest` manifest key)
+    pub doctest: bool,
+    package: PackageId,
+    workspace_root: Utf8PathBuf,
+}
+
+/// See [`Target::kind`]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum TargetKind {
+    /// The package's library, with its crate types
+    Lib(Vec<CrateType>),
+    /// A binary
+    Bin,
+    /// An example. Usually an executable, but it can be a library.
+    Example,
+    /// An integration test
+    Test,
+    /// A benchmark
+    Bench,
+    /// The package's build script
+    BuildScript,
+}
+
+/// The parts of a `Cargo.toml` that `cargo metadata` leaves out
+#[derive(Deserialize, Default)]
+struct RawManifest {
+    lib: Option<RawTarget>,
+    #[serde(default)]
+    bin: Vec<RawTarget>,
+    #[serde(default)]
+    example: Vec<RawTarget>,
+    #[serde(default)]
+    test: Vec<RawTarget>,
+    #[serde(default)]
+    bench: Vec<RawTarget>,
+}
+
+#[derive(Deserialize)]
+struct RawTarget {
+    name: Option<String>,
+    harness: Option<bool>,
+}
+
+impl RawManifest {
+    #[instrument(err)]
+    fn read(path: &Utf8Path) -> Result<Self, BuildError> {
+        let manifest = fs::read_to_string(path)
+            .map_err(|err| BuildError::Manifest(format!("reading {path}: {err}")))?;
+        toml::from_str(&manifest)
+            .map_err(|err| BuildError::Manifest(format!("parsing {path}: {err}")))
+    }
+
+    fn harness(&self, name: &str, kind: &TargetKind) -> bool {
+        let targets = match kind {
+            // There's only one lib and its name is optional
+            TargetKind::Lib(_) => {
+                return self.lib.as_ref().and_then(|lib| lib.harness) != Some(false)
+            }
+            TargetKind::Bin => &self.bin,
+            TargetKind::Example => &self.example,
+            TargetKind::Test => &self.test,
+            TargetKind::Bench => &self.bench,
+            TargetKind::BuildScript => return false,
+        };
+        targets
+            .iter()
+            .find(|target| target.name.as_deref() == Some(name))
+            .and_then(|target| target.harness)
+            != Some(false)
+    }
+}
+
+impl Workspace {
+    /// Run `cargo metadata` in `dir`, which is anywhere in the workspace.
+    ///
+    /// # Errors
+    ///
+    /// If cargo couldn't be run or failed, or we couldn't read the
+    /// `Cargo.toml` of a member.
+    #[instrument(err)]
+    pub fn load(dir: impl Into<PathBuf> + std::fmt::Debug) -> Result<Self, BuildError> {
+        let opts = Self::options(dir.into());
+        Self::from_metadata(&opts.metadata(&opts.deadline())?)
+    }
+
+    /// Like [`Self::load`], but doesn't block the thread while cargo runs.
+    ///
+    /// # Errors
+    ///
+    /// See [`Self::load`].
+    #[cfg(feature = "tokio")]
+    #[instrument(err)]
+    pub async fn load_async(dir: impl Into<PathBuf> + std::fmt::Debug) -> Result<Self, BuildError> {
+        let opts = Self::options(dir.into());
+        Self::from_metadata(&opts.metadata_async(&opts.deadline()).await?)
+    }
+
+    fn options(dir: PathBuf) -> CargoOptions {
+        let mut opts = CargoOptions::new();
+        opts.workspace = Some(dir);
+        opts
+    }
+
+    fn from_metadata(metadata: &Metadata) -> Result<Self, BuildError> {
+        let root = metadata.inner.workspace_root.clone();
+        let members = metadata
+            .members()
+            .map(|package| Package::new(package, metadata, &root))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            members,
+            target_directory: metadata.inner.target_directory.clone(),
+            root,
+        })
+    }
+
+    /// The member named `name`
+    #[must_use]
+    pub fn package(&self, name: &str) -> Option<&Package> {
+        self.members.iter().find(|package| package.name == name)
+    }
+
+    /// Every target of every member
+    pub fn targets(&self) -> impl Iterator<Item = &Target> {
+        self.members.iter().flat_map(|package| &package.targets)
+    }
+}
+
+impl Package {
+    fn new(
+        package: &cargo_metadata::Package,
+        metadata: &Metadata,
+        workspace_root: &Utf8Path,
+    ) -> Result<Self, BuildError> {
+        let manifest = RawManifest::read(&package.manifest_path)?;
+
+        let targets = package
+            .targets
+            .iter()
+            .map(|target| {
+                let kind = TargetKind::from_cargo(&target.kind);
+                Target {
+                    harness: manifest.harness(&target.name, &kind),
+                    name: target.name.clone(),
+                    kind,
+                    src_path: target.src_path.clone(),
+                    required_features: target.required_features.clone(),
+                    test: target.test,
+                    doctest: target.doctest,
+                    package: package.id.clone(),
+                    workspace_root: workspace_root.to_owned(),
+                }
+            })
+            .collect();
+
+        let mut features: Vec<_> = package.features.keys().cloned().collect();
+        features.sort();
+
+        Ok(Self {
+            id: package.id.clone(),
+            name: package.name.clone(),
+            version: package.version.to_string(),
+            manifest_path: package.manifest_path.clone(),
+            default_run: metadata.default_run(&package.id).map(str::to_owned),
+            features,
+            targets,
+        })
+    }
+
+    /// Select this package in a compiler
+    #[must_use]
+    pub fn spec(&self) -> PackageSpec {
+        PackageSpec::Id(self.id.clone())
+    }
+
+    /// The package's library, if it has one
+    #[must_use]
+    pub fn lib(&self) -> Option<&Target> {
+        self.targets
+            .iter()
+            .find(|target| matches!(target.kind, TargetKind::Lib(_)))
+    }
+
+    /// The package's binaries
+    pub fn bins(&self) -> impl Iterator<Item = &Target> {
+        self.targets_of_kind(TargetKind::Bin)
+    }
+
+    /// The package's examples
+    pub fn examples(&self) -> impl Iterator<Item = &Target> {
+        self.targets_of_kind(TargetKind::Example)
+    }
+
+    /// The package's integration tests
+    pub fn tests(&self) -> impl Iterator<Item = &Target> {
+        self.targets_of_kind(TargetKind::Test)
+    }
+
+    /// The package's benchmarks
+    pub fn benches(&self) -> impl Iterator<Item = &Target> {
+        self.targets_of_kind(TargetKind::Bench)
+    }
+
+    fn targets_of_kind(&self, kind: TargetKind) -> impl Iterator<Item = &Target> {
+        self.targets
+            .iter()
+            .filter(move |target| target.kind == kind)
+    }
+}
+
+impl TargetKind {
+    fn from_cargo(kinds: &[String]) -> Self {
+        match kinds.first().map(String::as_str) {
+            Some("bin") => Self::Bin,
+            Some("example") => Self::Example,
+            Some("test") => Self::Test,
+            Some("bench") => Self::Bench,
+            Some("custom-build") => Self::BuildScript,
+            // A lib's kinds are its crate types
+            _ => Self::Lib(
+                kinds
+                    .iter()
+                    .filter_map(|kind| CrateType::from_cargo(kind))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl Target {
+    /// The package the target is in
+    #[must_use]
+    pub fn package_spec(&self) -> PackageSpec {
+        PackageSpec::Id(self.package.clone())
+    }
+
+    /// What to pass to [`test::Compiler::new`] to compile the target's tests.
+    /// `None` for a build script.
+    #[must_use]
+    pub fn type_spec(&self) -> Option<TypeSpec> {
+        let spec = match self.kind {
+            TargetKind::Lib(_) => TypeSpec::Lib,
+            TargetKind::Bin => TypeSpec::bin(&self.name),
+            TargetKind::Example => TypeSpec::example(&self.name),
+            TargetKind::Test => TypeSpec::integration(&self.name),
+            TargetKind::Bench => TypeSpec::bench(&self.name),
+            TargetKind::BuildScript => return None,
+        };
+        Some(spec)
+    }
+
+    /// A compiler for this bin or example, run in the workspace with its
+    /// required features enabled. `None` for other kinds of target.
+    #[must_use]
+    pub fn bin_compiler(&self) -> Option<bin::Compiler> {
+        let mut compiler = match self.kind {
+            TargetKind::Bin => bin::Compiler::bin(&self.name),
+            TargetKind::Example => bin::Compiler::example(&self.name),
+            _ => return None,
+        };
+        compiler
+            .workspace(&self.workspace_root)
+            .package(self.package_spec());
+        if let Some(features) = self.features() {
+            compiler.features(features);
+        }
+        Some(compiler)
+    }
+
+    /// A compiler for this lib, run in the workspace. `None` for other kinds
+    /// of target.
+    #[must_use]
+    pub fn lib_compiler(&self) -> Option<library::Compiler> {
+        if !matches!(self.kind, TargetKind::Lib(_)) {
+            return None;
+        }
+        let mut compiler = library::Compiler::lib();
+        compiler
+            .workspace(&self.workspace_root)
+            .package(self.package_spec());
+        Some(compiler)
+    }
+
+    /// A compiler for the tests in this target that match `name`, run in the
+    /// workspace with its required features enabled. `None` for a build
+    /// script.
+    #[must_use]
+    pub fn test_compiler(&self, name: NameSpec) -> Option<test::Compiler> {
+        let mut compiler = test::Compiler::new(name, self.type_spec()?);
+        compiler
+            .workspace(&self.workspace_root)
+            .package(self.package_spec());
+        if let Some(features) = self.features() {
+            compiler.features(features);
+        }
+        Some(compiler)
+    }
+
+    fn features(&self) -> Option<FeatureSpec> {
+        if self.required_features.is_empty() {
+            None
+        } else {
+            Some(FeatureSpec::new(self.required_features.clone()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_common::{init, Result};
+    use pretty_assertions::assert_eq;
+
+    fn names<'a>(targets: impl Iterator<Item = &'a Target>) -> Vec<&'a str> {
+        targets.map(|target| target.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_members() -> Result {
+        init();
+        let workspace = Workspace::load("samples/hello_world")?;
+        let mut members: Vec<_> = workspace
+            .members
+            .iter()
+            .map(|package| package.name.as_str())
+            .collect();
+        members.sort_unstable();
+        assert_eq!(vec!["hello_world", "ws_member"], members);
+        assert!(workspace.root.ends_with("samples/hello_world"));
+
+        let package = workspace.package("hello_world").unwrap();
+        assert_eq!("0.1.0", package.version);
+        assert_eq!(
+            vec!["default", "default_feature", "non_default_feature"],
+            package.features
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_targets() -> Result {
+        init();
+        let workspace = Workspace::load("samples/targets")?;
+        let package = workspace.package("targets").unwrap();
+
+        let lib = package.lib().unwrap();
+        assert_eq!(TargetKind::Lib(vec![CrateType::Rlib]), lib.kind);
+        assert!(lib.doctest);
+
+        let mut bins = names(package.bins());
+        bins.sort_unstable();
+        assert_eq!(vec!["needs_extra", "untested"], bins);
+        assert_eq!(vec!["example"], names(package.examples()));
+        assert_eq!(vec!["bench"], names(package.benches()));
+
+        let mut tests = names(package.tests());
+        tests.sort_unstable();
+        assert_eq!(vec!["integration", "no_harness"], tests);
+        Ok(())
+    }
+
+    #[test]
+    fn test_manifest_keys() -> Result {
+        init();
+        let workspace = Workspace::load("samples/targets")?;
+        let target = |name: &str| {
+            workspace
+                .targets()
+                .find(|target| target.name == name)
+                .unwrap()
+        };
+
+        assert_eq!(vec!["extra"], target("needs_extra").required_features);
+        assert!(target("needs_extra").test);
+        assert!(!target("untested").test);
+        assert!(target("integration").harness);
+        assert!(!target("no_harness").harness);
+        assert!(target("bench").harness);
+        Ok(())
+    }
+
+    #[test]
+    fn test_default_run() -> Result {
+        init();
+        let workspace = Workspace::load("samples/default_run")?;
+        let package = workspace.package("default_run").unwrap();
+        assert_eq!(Some("second"), package.default_run.as_deref());
+        Ok(())
+    }
+
+    #[test]
+    fn test_bin_compiler() -> Result {
+        init();
+        let workspace = Workspace::load("samples/targets")?;
+        let package = workspace.package("targets").unwrap();
+        let bin = package
+            .bins()
+            .find(|bin| bin.name == "needs_extra")
+            .unwrap();
+
+        // Only builds if we enable the required feature
+        let artifact = bin.bin_compiler().unwrap().compile()?;
+        assert_eq!("needs_extra", artifact.target.name);
+        assert_eq!(package.id, artifact.package_id);
+        Ok(())
+    }
+
+    #[test]
+    fn test_lib_compiler() -> Result {
+        init();
+        let workspace = Workspace::load("samples/targets")?;
+        let lib = workspace.package("targets").unwrap().lib().unwrap();
+        assert!(lib.bin_compiler().is_none());
+
+        let artifacts = lib.lib_compiler().unwrap().compile()?;
+        assert_eq!(1, artifacts.len());
+        assert_eq!(CrateType::Rlib, artifacts[0].crate_type);
+        Ok(())
+    }
+
+    #[test]
+    fn test_test_compiler() -> Result {
+        init();
+        let workspace = Workspace::load("samples/targets")?;
+        let bench = workspace
+            .package("targets")
+            .unwrap()
+            .benches()
+            .next()
+            .unwrap();
+        assert_eq!(Some(TypeSpec::bench("bench")), bench.type_spec());
+
+        let mut artifacts = bench.test_compiler(NameSpec::Any).unwrap().compile()?;
+        assert_eq!(1, artifacts.len());
+        let artifact = artifacts.pop().unwrap();
+        assert_eq!("bench", artifact.artifact.target.name);
+        assert_eq!(
+            vec!["test_in_bench"],
+            artifact
+                .tests
+                .iter()
+                .map(|test| test.name.as_str())
+                .collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+}